@@ -1,4 +1,63 @@
+/// The name that the websocket server registers with xous-names.
+pub const SERVER_NAME: &str = "_Websocket Server_";
+
+/// The number of connections that xous-names will broker to the websocket server. Each
+/// process uses one of these for its `WebSocketService`, which every call to
+/// `WebSocketService::new()` shares.
+pub const MAX_CLIENT_CONNECTIONS: u32 = 16;
+
 /// Opcodes
+///
+/// * `Open`: `lend_mut` one or more pages containing the UTF-8 URL, with `valid` set to the
+///   length of the contents and `offset` set to the value returned by `open_offset()`. The
+///   URL may be followed by `\r\n` and extra headers for the upgrade request, which are sent as they
+///   are. A `Sec-WebSocket-Protocol` header lists the subprotocols to request. Headers that
+///   the handshake sets itself, and hop-by-hop headers, are refused with
+///   `Error::ForbiddenHeader`. If anything follows the blank line that ends the headers, it
+///   is a list of root certificates to trust for `wss://`, each a big-endian `u32` length
+///   and then the DER-encoded certificate. The page is returned once the opening
+///   handshake has finished, with `offset` set to the new `WebSocketFd` and `valid` set to
+///   `None`. On failure `valid` is an `Error` code, and `offset` holds the HTTP status for
+///   `Error::BadStatus`. In both cases the page holds the status line and headers of the
+///   server's response, if there was one, followed by zeroes.
+/// * `Send`: `lend` one or more pages containing the payload, with `offset` set to the value
+///   returned by `send_offset()` and `valid` set to the length of the payload. The pages are
+///   returned with `offset` set to the number of bytes accepted and `valid` set to an `Error`
+///   code, if any.
+/// * `Poll`: `lend_mut` an empty page. It is returned when data arrives on any connection
+///   owned by the calling process, with `offset` set to the value built by `poll_offset()`
+///   and `valid` set to the number of bytes in the page. Messages larger than the page are
+///   split across several responses using `POLL_FLAG_MORE`. If a process lets several
+///   megabytes pile up without polling, its connections that receive more are failed with
+///   `close_code::POLICY_VIOLATION`.
+/// * `Close`: `lend` a page containing the UTF-8 close reason, with `offset` set to the
+///   value returned by `close_offset()` and `valid` set to the length of the reason. The
+///   page is returned with `valid` set to an `Error` code, if any. Once the remote side
+///   responds, a `Poll` response with `POLL_FLAG_CLOSED` is sent. If it doesn't respond
+///   within a few seconds, the connection is dropped and reported as closed with
+///   `close_code::ABNORMAL`. Closing a listener stops
+///   it from accepting connections, and the code and reason are ignored.
+/// * `State`: blocking scalar with `arg1` set to the `WebSocketFd`. Returns a `Scalar1`
+///   containing a `ConnectionState`, or `0` if the connection doesn't exist.
+/// * `Tick`: scalar. The server sends this to itself every second, and uses it to send
+///   keepalive pings and to find connections that have stopped answering them.
+/// * `Quit`: scalar or blocking scalar. Every connection is closed with
+///   `close_code::GOING_AWAY`, every outstanding `Poll` is returned with
+///   `POLL_FLAG_SHUTDOWN`, and the server exits. A blocking scalar returns `0` first.
+/// * `Listen`: blocking scalar with `arg1` set to a TCP port and `arg2` set to the value
+///   returned by `open_offset()`, which applies to every accepted connection. Returns a
+///   `Scalar2` containing a `WebSocketFd` for the listener and `0`, or `0` and an `Error` code. Each
+///   connection that is accepted is reported with a `Poll` response for the listener that
+///   has `POLL_FLAG_ACCEPTED` set.
+/// * `Select`: `lend` a page containing the subprotocol chosen for a connection that was
+///   reported with `POLL_FLAG_SELECT`, with `offset` set to its `WebSocketFd` and `valid`
+///   set to the length of the name. An empty page chooses no subprotocol. The page is
+///   returned with `valid` set to an `Error` code, if any.
+/// * `Disconnect`: blocking scalar. Like `Quit`, but only for the calling process: its
+///   connections are closed with `close_code::GOING_AWAY`, its listeners are stopped, and
+///   its outstanding `Poll`s are returned with `POLL_FLAG_SHUTDOWN`. If it has none, its
+///   next `Poll` is returned that way instead. Returns a `Scalar1` containing `0`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Opcodes {
     Close = 1,
     Open = 2,
@@ -7,5 +66,476 @@ pub enum Opcodes {
     State = 5,
     Tick = 6,
     Quit = 7,
+    Listen = 8,
+    Select = 9,
+    Disconnect = 10,
+}
+
+impl TryFrom<usize> for Opcodes {
+    type Error = usize;
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Opcodes::Close),
+            2 => Ok(Opcodes::Open),
+            3 => Ok(Opcodes::Poll),
+            4 => Ok(Opcodes::Send),
+            5 => Ok(Opcodes::State),
+            6 => Ok(Opcodes::Tick),
+            7 => Ok(Opcodes::Quit),
+            8 => Ok(Opcodes::Listen),
+            9 => Ok(Opcodes::Select),
+            10 => Ok(Opcodes::Disconnect),
+            other => Err(other),
+        }
+    }
+}
+
+/// The most that the extra headers passed to `Open` may add up to, counting each one as
+/// `Name: value\r\n`.
+pub const MAX_REQUEST_HEADERS: usize = 4096;
+
+/// Passed to `Open` or `Listen` to turn off `permessage-deflate` compression, which is
+/// otherwise negotiated whenever the other side supports it.
+pub const OPEN_FLAG_NO_COMPRESSION: usize = 1 << 0;
+
+/// Passed to `Listen` when the owner wants to choose subprotocols itself. Connections that
+/// offer any are reported with `POLL_FLAG_SELECT`, and wait for a `Select` message before
+/// the handshake is answered. Without this flag, no subprotocol is chosen.
+pub const OPEN_FLAG_SELECT_PROTOCOL: usize = 1 << 1;
+
+/// Passed to `Open` to trust only the root certificates that came with it, rather than
+/// adding them to the built-in set.
+pub const OPEN_FLAG_NO_BUILTIN_ROOTS: usize = 1 << 2;
+
+/// Pack `OPEN_FLAG_*` bits and keepalive settings into the `offset` field of an `Open`
+/// message, or `arg2` of a `Listen` message. A connection that has been idle for
+/// `ping_interval` seconds is pinged, and it's closed once `max_missed_pongs` pings in a
+/// row go unanswered. An interval of `0` turns keepalive pings off.
+///
+/// | Bits  | Contents                        |
+/// |-------|---------------------------------|
+/// | 0-7   | `OPEN_FLAG_*` bits              |
+/// | 8-23  | Ping interval, in seconds       |
+/// | 24-31 | Pongs that may be missed        |
+pub fn open_offset(flags: usize, ping_interval: u16, max_missed_pongs: u8) -> usize {
+    (flags & 0xff) | (ping_interval as usize) << 8 | (max_missed_pongs as usize) << 24
+}
+
+/// Unpack the value built by `open_offset()` into `OPEN_FLAG_*` bits, a ping interval and
+/// the number of pongs that may be missed.
+pub fn parse_open_offset(offset: usize) -> (usize, u16, u8) {
+    (
+        offset & 0xff,
+        ((offset >> 8) & 0xffff) as u16,
+        ((offset >> 24) & 0xff) as u8,
+    )
+}
+
+/// The close reason that is reported when a connection stopped answering keepalive pings,
+/// or didn't finish the closing handshake in time. It comes with `close_code::ABNORMAL`,
+/// since the close frames weren't both exchanged.
+pub const TIMEOUT_REASON: &str = "Timeout";
+
+/// The state of a connection. Connections move through these states in order, except that
+/// any state may move directly to `Closed` if the connection drops.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection is still being established.
+    Connecting = 1,
+    /// The connection is established, and data may be sent and received.
+    Open = 2,
+    /// A close has been requested, and the remote side hasn't finished closing yet.
+    Closing = 3,
+    /// The connection is closed.
+    Closed = 4,
 }
 
+impl ConnectionState {
+    pub fn from_usize(value: usize) -> Option<ConnectionState> {
+        match value {
+            1 => Some(ConnectionState::Connecting),
+            2 => Some(ConnectionState::Open),
+            3 => Some(ConnectionState::Closing),
+            4 => Some(ConnectionState::Closed),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if a connection may move from this state to `next`.
+    pub fn can_transition_to(&self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (Connecting, Open) | (Open, Closing) | (Connecting | Open | Closing, Closed)
+        )
+    }
+}
+
+/// The kind of a message. This is passed to `Send`, and reported in `Poll` responses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A UTF-8 text message.
+    Text = 1,
+    /// A binary message.
+    Binary = 2,
+    /// A ping control frame. The server answers these itself, so they are only reported.
+    Ping = 3,
+    /// A pong control frame, sent in response to a ping.
+    Pong = 4,
+    /// A close control frame, holding a big-endian close code followed by the reason.
+    Close = 5,
+}
+
+impl MessageKind {
+    pub fn from_usize(value: usize) -> Option<MessageKind> {
+        match value {
+            1 => Some(MessageKind::Text),
+            2 => Some(MessageKind::Binary),
+            3 => Some(MessageKind::Ping),
+            4 => Some(MessageKind::Pong),
+            5 => Some(MessageKind::Close),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` for ping, pong and close, which are control frames rather than
+    /// application data.
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            MessageKind::Ping | MessageKind::Pong | MessageKind::Close
+        )
+    }
+}
+
+/// Pack a `WebSocketFd` and a `MessageKind` into the `offset` field of a `Send` message.
+/// The fd occupies the lower 16 bits, and the kind sits above it.
+pub fn send_offset(fd: u16, kind: MessageKind) -> usize {
+    fd as usize | (kind as usize) << 16
+}
+
+/// Unpack the `offset` field of a `Send` message into a `WebSocketFd` and a `MessageKind`.
+pub fn parse_send_offset(offset: usize) -> (u16, Option<MessageKind>) {
+    (
+        (offset & 0xffff) as u16,
+        MessageKind::from_usize(offset >> 16),
+    )
+}
+
+/// Mask for the `WebSocketFd` in the `offset` field of a `Poll` response. The whole field
+/// is laid out as follows, so that it fits in a 32-bit `usize`:
+///
+/// | Bits  | Contents                   |
+/// |-------|----------------------------|
+/// | 0-15  | `WebSocketFd`              |
+/// | 16-23 | `POLL_FLAG_*` bits         |
+/// | 24-27 | `MessageKind`              |
+/// | 28-31 | Reserved, and must be zero |
+pub const POLL_FD_MASK: usize = 0xffff;
+
+/// Mask for the `POLL_FLAG_*` bits in the `offset` field of a `Poll` response.
+pub const POLL_FLAG_MASK: usize = 0xff << 16;
+
+/// Position of the `MessageKind` in the `offset` field of a `Poll` response.
+pub const POLL_KIND_SHIFT: usize = 24;
+
+/// Mask for the `MessageKind` in the `offset` field of a `Poll` response.
+pub const POLL_KIND_MASK: usize = 0xf << POLL_KIND_SHIFT;
+
+/// Set in the `offset` field of a `Poll` response when the connection has closed. The page
+/// holds the close code as a big-endian `u16` followed by the UTF-8 reason, which is the
+/// same layout as the payload of a close frame. No more data will arrive for this
+/// `WebSocketFd` afterwards.
+pub const POLL_FLAG_CLOSED: usize = 1 << 16;
+
+/// Set in the `offset` field of a `Poll` response when the message didn't fit in the page.
+/// The rest of the message follows in later `Poll` responses for the same `WebSocketFd`,
+/// and the last piece is the one without this flag.
+pub const POLL_FLAG_MORE: usize = 1 << 17;
+
+/// Set in the `offset` field of a `Poll` response for a listener when a connection has been
+/// accepted and has finished its opening handshake. The page holds the new connection's
+/// `WebSocketFd` as a big-endian `u16`, followed by the status line and headers of the
+/// client's request including the blank line that ends them, followed by the subprotocol
+/// that was chosen, if any.
+pub const POLL_FLAG_ACCEPTED: usize = 1 << 18;
+
+/// Set in the `offset` field of a `Poll` response for a listener that was opened with
+/// `OPEN_FLAG_SELECT_PROTOCOL`, when an incoming connection offers subprotocols. The page
+/// holds the connection's `WebSocketFd` as a big-endian `u16`, followed by the offered
+/// subprotocols separated by `, `. The owner answers with a `Select` message.
+pub const POLL_FLAG_SELECT: usize = 1 << 19;
+
+/// Set in the `offset` field of a `Poll` response when the server is shutting down, or has
+/// been asked to with `Disconnect`. Every connection and listener that the process had is
+/// gone, and nothing more will arrive. The `WebSocketFd` and kind are zero, and the page
+/// is empty.
+pub const POLL_FLAG_SHUTDOWN: usize = 1 << 20;
+
+/// Pack a `WebSocketFd`, a `MessageKind` and `POLL_FLAG_*` bits into the `offset` field of
+/// a `Poll` response.
+pub fn poll_offset(fd: u16, kind: MessageKind, flags: usize) -> usize {
+    fd as usize | (flags & POLL_FLAG_MASK) | (kind as usize) << POLL_KIND_SHIFT
+}
+
+/// Unpack the `offset` field of a `Poll` response into a `WebSocketFd`, a `MessageKind`
+/// and the `POLL_FLAG_*` bits.
+pub fn parse_poll_offset(offset: usize) -> (u16, Option<MessageKind>, usize) {
+    (
+        (offset & POLL_FD_MASK) as u16,
+        MessageKind::from_usize((offset & POLL_KIND_MASK) >> POLL_KIND_SHIFT),
+        offset & POLL_FLAG_MASK,
+    )
+}
+
+/// Pack a `WebSocketFd` and a close code into the `offset` field of a `Close` message.
+pub fn close_offset(fd: u16, code: u16) -> usize {
+    fd as usize | (code as usize) << 16
+}
+
+/// Unpack the `offset` field of a `Close` message into a `WebSocketFd` and a close code.
+pub fn parse_close_offset(offset: usize) -> (u16, u16) {
+    ((offset & 0xffff) as u16, (offset >> 16) as u16)
+}
+
+/// The longest close reason that fits in a close frame, since control frames are limited
+/// to 125 bytes and two of those are taken up by the close code.
+pub const MAX_CLOSE_REASON: usize = 123;
+
+/// Close codes from RFC 6455 section 7.4.1.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    /// Never sent, but reported when a close frame arrives without a code.
+    pub const NO_STATUS: u16 = 1005;
+    /// Never sent, but reported when a connection drops without a close frame.
+    pub const ABNORMAL: u16 = 1006;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const MANDATORY_EXTENSION: u16 = 1010;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Returns `true` if `code` may be sent in a close frame. The reserved codes 1004,
+    /// 1005, 1006 and 1015 may not, and neither may anything below 1000 or above 4999.
+    pub fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+/// Error codes that the server returns to clients. These are passed back in the `valid` field
+/// of a memory response, or as the value of a scalar response. A value of `0` is never used,
+/// since it can't be represented in `valid`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The request was malformed, or used a message type the server doesn't expect.
+    InvalidRequest = 1,
+    /// The URL couldn't be parsed, or used an unsupported scheme.
+    InvalidUrl = 2,
+    /// The remote host couldn't be reached.
+    ConnectFailed = 3,
+    /// The `WebSocketFd` doesn't refer to a connection owned by the caller.
+    UnknownFd = 4,
+    /// All available `WebSocketFd`s are in use.
+    TooManyConnections = 5,
+    /// The connection failed while data was being written.
+    SendFailed = 6,
+    /// The connection isn't in a state that allows the request, for example sending
+    /// before it has finished opening.
+    InvalidState = 7,
+    /// A text message wasn't valid UTF-8.
+    InvalidUtf8 = 8,
+    /// The opening handshake didn't complete, for example because the connection dropped
+    /// or the response wasn't HTTP.
+    HandshakeFailed = 9,
+    /// The server responded to the opening handshake with a status other than 101. The
+    /// status is returned in `offset`.
+    BadStatus = 10,
+    /// The handshake response was missing `Upgrade: websocket` or `Connection: Upgrade`.
+    MissingHeader = 11,
+    /// The handshake response had the wrong `Sec-WebSocket-Accept`.
+    InvalidAccept = 12,
+    /// The server couldn't listen on the requested port.
+    ListenFailed = 13,
+    /// The handshake response accepted an extension that wasn't offered, or with
+    /// parameters that can't be used.
+    InvalidExtension = 14,
+    /// The handshake response chose a subprotocol that wasn't offered.
+    InvalidProtocol = 15,
+    /// The TLS handshake failed for a reason other than the server's certificate.
+    TlsFailed = 16,
+    /// The server's certificate didn't chain to a trusted root.
+    CertificateUntrusted = 17,
+    /// The server's certificate isn't valid for the host in the URL.
+    CertificateNameMismatch = 18,
+    /// The server's certificate has expired.
+    CertificateExpired = 19,
+    /// The server's certificate isn't valid yet.
+    CertificateNotYetValid = 20,
+    /// The server's certificate has been revoked.
+    CertificateRevoked = 21,
+    /// The server's certificate couldn't be used for some other reason, such as being
+    /// malformed or using an unsupported algorithm.
+    CertificateInvalid = 22,
+    /// A root certificate passed to `Open` couldn't be parsed.
+    InvalidRootCertificate = 23,
+    /// An extra header passed to `Open` had an invalid name or value.
+    InvalidHeader = 24,
+    /// An extra header passed to `Open` is one that can't be overridden.
+    ForbiddenHeader = 25,
+    /// The extra headers passed to `Open` were larger than `MAX_REQUEST_HEADERS`.
+    HeadersTooLarge = 26,
+}
+
+impl Error {
+    pub fn from_usize(value: usize) -> Option<Error> {
+        match value {
+            1 => Some(Error::InvalidRequest),
+            2 => Some(Error::InvalidUrl),
+            3 => Some(Error::ConnectFailed),
+            4 => Some(Error::UnknownFd),
+            5 => Some(Error::TooManyConnections),
+            6 => Some(Error::SendFailed),
+            7 => Some(Error::InvalidState),
+            8 => Some(Error::InvalidUtf8),
+            9 => Some(Error::HandshakeFailed),
+            10 => Some(Error::BadStatus),
+            11 => Some(Error::MissingHeader),
+            12 => Some(Error::InvalidAccept),
+            13 => Some(Error::ListenFailed),
+            14 => Some(Error::InvalidExtension),
+            15 => Some(Error::InvalidProtocol),
+            16 => Some(Error::TlsFailed),
+            17 => Some(Error::CertificateUntrusted),
+            18 => Some(Error::CertificateNameMismatch),
+            19 => Some(Error::CertificateExpired),
+            20 => Some(Error::CertificateNotYetValid),
+            21 => Some(Error::CertificateRevoked),
+            22 => Some(Error::CertificateInvalid),
+            23 => Some(Error::InvalidRootCertificate),
+            24 => Some(Error::InvalidHeader),
+            25 => Some(Error::ForbiddenHeader),
+            26 => Some(Error::HeadersTooLarge),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [MessageKind; 5] = [
+        MessageKind::Text,
+        MessageKind::Binary,
+        MessageKind::Ping,
+        MessageKind::Pong,
+        MessageKind::Close,
+    ];
+
+    /// Every offset has to fit in a 32-bit `usize`.
+    fn fits_in_u32(offset: usize) -> bool {
+        offset as u64 <= u32::MAX as u64
+    }
+
+    #[test]
+    fn send_offset_round_trip() {
+        for fd in [1, 0x1234, u16::MAX] {
+            for kind in KINDS {
+                let offset = send_offset(fd, kind);
+                assert!(fits_in_u32(offset));
+                assert_eq!(parse_send_offset(offset), (fd, Some(kind)));
+            }
+        }
+        assert_eq!(parse_send_offset(7), (7, None));
+    }
+
+    #[test]
+    fn poll_offset_round_trip() {
+        let flags = [
+            0,
+            POLL_FLAG_CLOSED,
+            POLL_FLAG_MORE,
+            POLL_FLAG_ACCEPTED | POLL_FLAG_MORE,
+            POLL_FLAG_SELECT,
+            POLL_FLAG_SHUTDOWN,
+        ];
+        for fd in [1, 0x1234, u16::MAX] {
+            for kind in KINDS {
+                for flags in flags {
+                    let offset = poll_offset(fd, kind, flags);
+                    assert!(fits_in_u32(offset));
+                    assert_eq!(parse_poll_offset(offset), (fd, Some(kind), flags));
+                }
+            }
+        }
+        // Bits outside the flags can't spill into the fd or the kind.
+        assert_eq!(
+            parse_poll_offset(poll_offset(5, MessageKind::Text, usize::MAX)),
+            (5, Some(MessageKind::Text), POLL_FLAG_MASK)
+        );
+        // A shutdown carries no fd or kind.
+        assert_eq!(
+            parse_poll_offset(POLL_FLAG_SHUTDOWN),
+            (0, None, POLL_FLAG_SHUTDOWN)
+        );
+    }
+
+    #[test]
+    fn close_offset_round_trip() {
+        for fd in [1, u16::MAX] {
+            for code in [close_code::NORMAL, close_code::MESSAGE_TOO_BIG, 4999] {
+                let offset = close_offset(fd, code);
+                assert!(fits_in_u32(offset));
+                assert_eq!(parse_close_offset(offset), (fd, code));
+            }
+        }
+    }
+
+    #[test]
+    fn open_offset_round_trip() {
+        let all_flags =
+            OPEN_FLAG_NO_COMPRESSION | OPEN_FLAG_SELECT_PROTOCOL | OPEN_FLAG_NO_BUILTIN_ROOTS;
+        for flags in [0, OPEN_FLAG_NO_COMPRESSION, all_flags] {
+            for interval in [0, 30, u16::MAX] {
+                for missed in [0, 3, u8::MAX] {
+                    let offset = open_offset(flags, interval, missed);
+                    assert!(fits_in_u32(offset));
+                    assert_eq!(parse_open_offset(offset), (flags, interval, missed));
+                }
+            }
+        }
+        // Flags that don't fit in their byte are dropped rather than changing the interval.
+        assert_eq!(parse_open_offset(open_offset(0x100, 30, 3)), (0, 30, 3));
+    }
+
+    #[test]
+    fn state_transitions() {
+        use ConnectionState::*;
+        let states = [Connecting, Open, Closing, Closed];
+        let allowed = [
+            (Connecting, Open),
+            (Connecting, Closed),
+            (Open, Closing),
+            (Open, Closed),
+            (Closing, Closed),
+        ];
+        for from in states {
+            for to in states {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    allowed.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+        for state in states {
+            assert_eq!(ConnectionState::from_usize(state as usize), Some(state));
+        }
+        assert_eq!(ConnectionState::from_usize(0), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! `futures` `Stream` and `Sink` implementations for `WebSocketStream`, for use from async
+//! code. The poll thread wakes the task that's waiting on a stream whenever it hands the
+//! stream something, and sending happens on a thread of its own, so nothing here blocks.
+
+use crate::{api, Link, MessageKind, WebSocketError, WebSocketPacket, WebSocketStream};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Packets as they arrive, and errors such as `WebSocketError::Closed` with the close code
+/// and reason. The stream ends once the connection is closed. Reconnect events are skipped,
+/// as with the `Iterator` implementation.
+impl futures_core::Stream for WebSocketStream {
+    type Item = Result<WebSocketPacket, WebSocketError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Register before checking, so that something arriving in between still wakes
+        // the task.
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        loop {
+            match self.receiver.try_recv() {
+                Ok(Err(WebSocketError::Reconnecting { .. } | WebSocketError::Reconnected)) => (),
+                Ok(item) => return Poll::Ready(Some(item)),
+                Err(mpsc::TryRecvError::Disconnected) => return Poll::Ready(None),
+                Err(mpsc::TryRecvError::Empty) => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// The thread that a stream's `Sink` implementations send on. Handing a message to the
+/// server blocks until it has been written out, which mustn't happen on the executor. One
+/// message is sent at a time.
+pub(crate) struct SinkThread {
+    queue: mpsc::Sender<(MessageKind, Vec<u8>)>,
+    state: Arc<Mutex<SinkState>>,
+}
+
+#[derive(Default)]
+struct SinkState {
+    /// Set while a message is waiting to be sent.
+    busy: bool,
+    /// What went wrong with the last message, returned by the next call to the sink.
+    error: Option<WebSocketError>,
+    /// The task waiting for the message to be sent.
+    waker: Option<Waker>,
+}
+
+impl SinkThread {
+    /// Start the thread. It exits once the stream is dropped.
+    fn spawn(cid: xous::CID, link: Arc<Mutex<Link>>) -> Result<SinkThread, WebSocketError> {
+        let (queue, messages) = mpsc::channel::<(MessageKind, Vec<u8>)>();
+        let state = Arc::new(Mutex::new(SinkState::default()));
+        let shared = state.clone();
+        std::thread::Builder::new()
+            .name("websocket send".to_owned())
+            .spawn(move || {
+                for (kind, data) in messages {
+                    // Look the fd up for each message, since it changes if the stream
+                    // reconnects.
+                    let fd = link.lock().unwrap().fd;
+                    let result = crate::lend_to_server(
+                        cid,
+                        api::Opcodes::Send,
+                        api::send_offset(fd.0, kind),
+                        &data,
+                    );
+                    let mut state = shared.lock().unwrap();
+                    state.busy = false;
+                    state.error = result.err();
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                }
+            })
+            .map_err(|_| WebSocketError::ThreadSpawnFailed)?;
+        Ok(SinkThread { queue, state })
+    }
+}
+
+impl WebSocketStream {
+    /// `Pending` until the last message has been sent, and then its result.
+    fn poll_sent(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), WebSocketError>> {
+        let Some(sink) = &self.sink else {
+            return Poll::Ready(Ok(()));
+        };
+        let mut state = sink.state.lock().unwrap();
+        if state.busy {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(state.error.take().map_or(Ok(()), Err))
+    }
+
+    /// Hand a message to the sink's thread, starting it if this is the first.
+    fn start_sending(&mut self, kind: MessageKind, data: Vec<u8>) -> Result<(), WebSocketError> {
+        let sink = match &self.sink {
+            Some(sink) => sink,
+            None => self
+                .sink
+                .insert(SinkThread::spawn(self.cid, self.link.clone())?),
+        };
+        sink.state.lock().unwrap().busy = true;
+        sink.queue
+            .send((kind, data))
+            .map_err(|_| WebSocketError::ConnectionClosed)
+    }
+
+    /// Close the connection once the last message has been sent.
+    fn poll_close_sink(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), WebSocketError>> {
+        match self.poll_sent(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(self.close(api::close_code::NORMAL, "")),
+            other => other,
+        }
+    }
+}
+
+/// Sending a `String` sends a text message. The sink isn't ready for another message until
+/// the last one has been handed to the server, and an error in sending it is returned by
+/// the next call.
+impl futures_sink::Sink<String> for WebSocketStream {
+    type Error = WebSocketError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: String) -> Result<(), Self::Error> {
+        self.get_mut()
+            .start_sending(MessageKind::Text, item.into_bytes())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_close_sink(cx)
+    }
+}
+
+/// Sending a `Vec<u8>` sends a binary message, in the same way as `Sink<String>`.
+impl futures_sink::Sink<Vec<u8>> for WebSocketStream {
+    type Error = WebSocketError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.get_mut().start_sending(MessageKind::Binary, item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_close_sink(cx)
+    }
+}
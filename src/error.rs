@@ -0,0 +1,218 @@
+use crate::api;
+use crate::http::HttpHead;
+use std::time::Duration;
+
+/// Errors that can be returned by the websocket library.
+#[derive(Debug, PartialEq)]
+pub enum WebSocketError {
+    /// The URL was too long to fit in a single page.
+    UrlTooLong,
+    /// The server couldn't parse the URL, or doesn't support its scheme.
+    InvalidUrl,
+    /// The server couldn't connect to the remote host.
+    ConnectFailed,
+    /// The server doesn't know about this connection.
+    UnknownConnection,
+    /// The server has no more room for connections.
+    TooManyConnections,
+    /// Writing to the connection failed.
+    SendFailed,
+    /// The connection isn't in a state that allows this, for example it is still opening
+    /// or is already closing.
+    InvalidState,
+    /// The connection has been closed, and no more packets will arrive.
+    ConnectionClosed,
+    /// The connection was closed, either in response to `close()` or by the remote side.
+    /// `code` is the close code from the remote side, and `reason` is its explanation.
+    Closed { code: u16, reason: String },
+    /// The close code can't be sent in a close frame.
+    InvalidCloseCode,
+    /// The close reason is longer than 123 bytes.
+    ReasonTooLong,
+    /// A text message wasn't valid UTF-8.
+    InvalidUtf8,
+    /// The packet can't be viewed as the requested type, because its length isn't a
+    /// multiple of the type's size or it isn't suitably aligned.
+    InvalidLayout,
+    /// A message was larger than `ServiceConfig::max_message_size`, and was thrown away.
+    MessageTooLarge,
+    /// No packet is available yet.
+    WouldBlock,
+    /// No packet arrived before the timeout expired.
+    Timeout,
+    /// The server didn't understand the request.
+    InvalidRequest,
+    /// The server returned a response that didn't match the request.
+    UnexpectedResponse,
+    /// The thread that polls the server couldn't be started.
+    ThreadSpawnFailed,
+    /// The opening handshake didn't complete.
+    HandshakeFailed,
+    /// The server refused to upgrade the connection, and responded with HTTP status
+    /// `status`. `response` holds the status line and headers, which may say why, for
+    /// example with `WWW-Authenticate` or `Location`.
+    BadStatus { status: u16, response: HttpHead },
+    /// The server's handshake response was missing a required header.
+    MissingHeader,
+    /// The server's handshake response had the wrong `Sec-WebSocket-Accept`.
+    InvalidAccept,
+    /// The server couldn't listen on the requested port.
+    ListenFailed,
+    /// The server's handshake response accepted an extension that wasn't offered, or with
+    /// parameters that can't be used.
+    InvalidExtension,
+    /// A subprotocol name wasn't a valid HTTP token, or the server chose a subprotocol
+    /// that wasn't offered.
+    InvalidProtocol,
+    /// The TLS handshake failed for a reason other than the server's certificate.
+    TlsFailed,
+    /// The server's certificate didn't chain to a trusted root.
+    CertificateUntrusted,
+    /// The server's certificate isn't valid for the host in the URL.
+    CertificateNameMismatch,
+    /// The server's certificate has expired.
+    CertificateExpired,
+    /// The server's certificate isn't valid yet.
+    CertificateNotYetValid,
+    /// The server's certificate has been revoked.
+    CertificateRevoked,
+    /// The server's certificate couldn't be used for some other reason, such as being
+    /// malformed or using an unsupported algorithm.
+    CertificateInvalid,
+    /// One of `OpenOptions::root_certificates` couldn't be parsed.
+    InvalidRootCertificate,
+    /// An extra header or cookie had an invalid name or value.
+    InvalidHeader,
+    /// An extra header is one that the handshake sets itself, or a hop-by-hop header,
+    /// which can't be overridden.
+    ForbiddenHeader,
+    /// The extra headers and cookies added up to more than `api::MAX_REQUEST_HEADERS`.
+    HeadersTooLarge,
+    /// The connection dropped, and attempt number `attempt` to reopen it will be made
+    /// after `delay`. This only happens for streams opened with a `ReconnectPolicy`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The connection was reopened after dropping, and packets will start arriving again.
+    Reconnected,
+    /// A call into the kernel failed.
+    Xous(xous::Error),
+}
+
+impl core::fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            WebSocketError::UrlTooLong => write!(f, "URL is too long"),
+            WebSocketError::InvalidUrl => write!(f, "URL is invalid or unsupported"),
+            WebSocketError::ConnectFailed => write!(f, "couldn't connect to the remote host"),
+            WebSocketError::UnknownConnection => write!(f, "connection doesn't exist"),
+            WebSocketError::TooManyConnections => write!(f, "too many open connections"),
+            WebSocketError::SendFailed => write!(f, "couldn't send data"),
+            WebSocketError::InvalidState => write!(f, "connection is in the wrong state"),
+            WebSocketError::ConnectionClosed => write!(f, "connection closed"),
+            WebSocketError::Closed { code, reason } => {
+                write!(f, "connection closed with code {}: {}", code, reason)
+            }
+            WebSocketError::InvalidCloseCode => write!(f, "close code may not be sent"),
+            WebSocketError::ReasonTooLong => write!(f, "close reason is too long"),
+            WebSocketError::InvalidUtf8 => write!(f, "text is not valid UTF-8"),
+            WebSocketError::InvalidLayout => {
+                write!(f, "packet doesn't match the size or alignment of the type")
+            }
+            WebSocketError::MessageTooLarge => write!(f, "message is too large"),
+            WebSocketError::WouldBlock => write!(f, "no packet available"),
+            WebSocketError::Timeout => write!(f, "timed out waiting for a packet"),
+            WebSocketError::InvalidRequest => write!(f, "the server rejected the request"),
+            WebSocketError::UnexpectedResponse => write!(f, "unexpected response from the server"),
+            WebSocketError::ThreadSpawnFailed => write!(f, "couldn't start the poll thread"),
+            WebSocketError::HandshakeFailed => write!(f, "opening handshake failed"),
+            WebSocketError::BadStatus { status, .. } => {
+                write!(f, "server responded with HTTP status {}", status)
+            }
+            WebSocketError::MissingHeader => write!(f, "handshake response is missing a header"),
+            WebSocketError::InvalidAccept => {
+                write!(f, "handshake response has the wrong Sec-WebSocket-Accept")
+            }
+            WebSocketError::ListenFailed => write!(f, "couldn't listen on the port"),
+            WebSocketError::InvalidExtension => {
+                write!(f, "handshake response has an unusable extension")
+            }
+            WebSocketError::InvalidProtocol => {
+                write!(f, "subprotocol is invalid or wasn't offered")
+            }
+            WebSocketError::TlsFailed => write!(f, "TLS handshake failed"),
+            WebSocketError::CertificateUntrusted => {
+                write!(f, "server certificate isn't from a trusted root")
+            }
+            WebSocketError::CertificateNameMismatch => {
+                write!(f, "server certificate doesn't match the host name")
+            }
+            WebSocketError::CertificateExpired => write!(f, "server certificate has expired"),
+            WebSocketError::CertificateNotYetValid => {
+                write!(f, "server certificate isn't valid yet")
+            }
+            WebSocketError::CertificateRevoked => write!(f, "server certificate has been revoked"),
+            WebSocketError::CertificateInvalid => write!(f, "server certificate is invalid"),
+            WebSocketError::InvalidRootCertificate => write!(f, "root certificate is invalid"),
+            WebSocketError::InvalidHeader => write!(f, "header name or value is invalid"),
+            WebSocketError::ForbiddenHeader => write!(f, "header can't be overridden"),
+            WebSocketError::HeadersTooLarge => write!(f, "request headers are too large"),
+            WebSocketError::Reconnecting { attempt, delay } => {
+                write!(f, "reconnecting in {:?} (attempt {})", delay, attempt)
+            }
+            WebSocketError::Reconnected => write!(f, "reconnected"),
+            WebSocketError::Xous(e) => write!(f, "kernel error: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {}
+
+impl From<xous::Error> for WebSocketError {
+    fn from(e: xous::Error) -> Self {
+        WebSocketError::Xous(e)
+    }
+}
+
+impl From<api::Error> for WebSocketError {
+    fn from(e: api::Error) -> Self {
+        match e {
+            api::Error::InvalidRequest => WebSocketError::InvalidRequest,
+            api::Error::InvalidUrl => WebSocketError::InvalidUrl,
+            api::Error::ConnectFailed => WebSocketError::ConnectFailed,
+            api::Error::UnknownFd => WebSocketError::UnknownConnection,
+            api::Error::TooManyConnections => WebSocketError::TooManyConnections,
+            api::Error::SendFailed => WebSocketError::SendFailed,
+            api::Error::InvalidState => WebSocketError::InvalidState,
+            api::Error::InvalidUtf8 => WebSocketError::InvalidUtf8,
+            api::Error::HandshakeFailed => WebSocketError::HandshakeFailed,
+            // Only `Open` fails like this, and the status and response come separately, so
+            // `send_open()` builds `BadStatus` itself.
+            api::Error::BadStatus => WebSocketError::HandshakeFailed,
+            api::Error::MissingHeader => WebSocketError::MissingHeader,
+            api::Error::InvalidAccept => WebSocketError::InvalidAccept,
+            api::Error::ListenFailed => WebSocketError::ListenFailed,
+            api::Error::InvalidExtension => WebSocketError::InvalidExtension,
+            api::Error::InvalidProtocol => WebSocketError::InvalidProtocol,
+            api::Error::TlsFailed => WebSocketError::TlsFailed,
+            api::Error::CertificateUntrusted => WebSocketError::CertificateUntrusted,
+            api::Error::CertificateNameMismatch => WebSocketError::CertificateNameMismatch,
+            api::Error::CertificateExpired => WebSocketError::CertificateExpired,
+            api::Error::CertificateNotYetValid => WebSocketError::CertificateNotYetValid,
+            api::Error::CertificateRevoked => WebSocketError::CertificateRevoked,
+            api::Error::CertificateInvalid => WebSocketError::CertificateInvalid,
+            api::Error::InvalidRootCertificate => WebSocketError::InvalidRootCertificate,
+            api::Error::InvalidHeader => WebSocketError::InvalidHeader,
+            api::Error::ForbiddenHeader => WebSocketError::ForbiddenHeader,
+            api::Error::HeadersTooLarge => WebSocketError::HeadersTooLarge,
+        }
+    }
+}
+
+impl WebSocketError {
+    /// Convert an error code from the server into a `WebSocketError`. Codes that this
+    /// version of the library doesn't know about are reported as `UnexpectedResponse`.
+    pub(crate) fn from_code(code: usize) -> WebSocketError {
+        api::Error::from_usize(code)
+            .map(|e| e.into())
+            .unwrap_or(WebSocketError::UnexpectedResponse)
+    }
+}
@@ -0,0 +1,129 @@
+//! Just enough HTTP/1.1 for the websocket opening handshake. This is shared between the
+//! server, which exchanges the handshake, and the library, which hands the result to
+//! applications.
+
+/// The start line and headers of an HTTP/1.1 request or response, without the blank line
+/// that ends them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpHead {
+    /// The request line or status line, for example `HTTP/1.1 101 Switching Protocols`.
+    pub start_line: String,
+    /// Headers in the order they appeared. Names keep their original case.
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpHead {
+    /// Parse a head. `bytes` may include the terminating blank line, and anything after it
+    /// is ignored. Returns `None` if a line isn't valid UTF-8 or a header has no `:`.
+    pub fn parse(bytes: &[u8]) -> Option<HttpHead> {
+        let end = head_len(bytes).unwrap_or(bytes.len());
+        let text = std::str::from_utf8(&bytes[..end]).ok()?;
+        let mut lines = text.split("\r\n").filter(|line| !line.is_empty());
+        let start_line = lines.next()?.to_owned();
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+        Some(HttpHead {
+            start_line,
+            headers,
+        })
+    }
+
+    /// Serialize the head, including the blank line that ends it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = self.start_line.clone() + "\r\n";
+        for (name, value) in &self.headers {
+            text += &format!("{}: {}\r\n", name, value);
+        }
+        text += "\r\n";
+        text.into_bytes()
+    }
+
+    /// The status code, if this is a response.
+    pub fn status(&self) -> Option<u16> {
+        let mut parts = self.start_line.split(' ');
+        if !parts.next()?.starts_with("HTTP/") {
+            return None;
+        }
+        parts.next()?.parse().ok()
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns `true` if the comma-separated header `name` contains `token`, ignoring case.
+    /// This is how `Connection: keep-alive, Upgrade` is matched.
+    pub fn header_has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+/// Returns `true` if `s` is a token as defined in RFC 7230 section 3.2.6, which is what
+/// header names and subprotocol names must be.
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Headers that a client may not add to the opening handshake. Hop-by-hop headers, from
+/// RFC 7230 section 6.1, only describe a single hop. The rest are set by the handshake
+/// itself, and overriding them would break it.
+const RESERVED_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Host",
+    "Content-Length",
+    "Sec-WebSocket-Accept",
+    "Sec-WebSocket-Extensions",
+    "Sec-WebSocket-Key",
+    "Sec-WebSocket-Version",
+];
+
+/// Returns `true` if `name` is a header that a client may not add to the opening
+/// handshake.
+pub fn is_reserved_header(name: &str) -> bool {
+    RESERVED_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Returns `true` if `value` can be sent as a header value. Control characters, and in
+/// particular line breaks, would let it smuggle in extra headers.
+pub fn is_header_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b'\t' || (b >= 0x20 && b != 0x7f))
+}
+
+/// Split a comma-separated header value into its trimmed, non-empty elements.
+pub fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty())
+}
+
+/// The length of the head at the start of `bytes`, including the blank line that ends it,
+/// or `None` if the end hasn't arrived yet.
+pub fn head_len(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|index| index + 4)
+}
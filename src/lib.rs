@@ -1,20 +1,223 @@
 pub mod api;
+#[cfg(feature = "async")]
+mod async_io;
+mod error;
+mod http;
+mod pod;
+mod pool;
 
+pub use api::{ConnectionState, MessageKind};
+pub use error::WebSocketError;
+pub use http::HttpHead;
+pub use pod::Pod;
+
+use pool::PagePool;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
+use std::task::Waker;
+use std::time::Duration;
 
 /// Representation of a websocket file descriptor
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 struct WebSocketFd(u16);
 
-struct WebSocketReceiver {
-    pipe: mpsc::Sender<WebSocketPacket>,
+/// Chooses a subprotocol for an incoming connection from the ones that the client offered,
+/// or `None` to choose nothing.
+type ProtocolSelector = Arc<dyn Fn(&[&str]) -> Option<String> + Send + Sync>;
+
+/// The connection behind a stream. Reconnecting replaces the fd, so this is shared between
+/// the stream and the thread that reconnects it.
+struct Link {
+    fd: WebSocketFd,
+    /// Set once the owner closes the stream, so that it isn't reopened.
+    closed: bool,
+}
+
+/// What's needed to reopen a stream's connection after it drops.
+struct Redial {
+    policy: ReconnectPolicy,
+    /// The `Open` request and offset, which are sent again as they were.
+    request: Vec<u8>,
+    offset: usize,
+    /// The subprotocol that the stream was opened with. A new connection has to agree on
+    /// it, since the owner is already speaking it.
+    protocol: Option<String>,
+    link: Arc<Mutex<Link>>,
+}
+
+/// Called with everything that arrives for a stream, in place of its pipe. Set with
+/// `WebSocketStream::set_handler()`.
+type PacketHandler = Box<dyn FnMut(Result<WebSocketPacket, WebSocketError>) + Send>;
+
+/// Where a stream keeps its handler. The handler is taken out while it runs, so that
+/// setting or clearing it doesn't have to wait, and it may even do that itself.
+#[derive(Default)]
+struct HandlerSlot {
+    handler: Option<PacketHandler>,
+    /// Changes whenever the handler is set or cleared, so that one that was taken out to
+    /// run is only put back if that didn't happen in the meantime.
+    generation: u64,
+}
+
+impl HandlerSlot {
+    fn replace(&mut self, handler: Option<PacketHandler>) {
+        self.handler = handler;
+        self.generation = self.generation.wrapping_add(1);
+    }
+}
+
+/// The poll thread's end of a stream's pipe. Sending also wakes the task that's waiting on
+/// the stream, if it's being polled asynchronously. If the stream has a handler, it's
+/// called instead.
+#[derive(Clone)]
+struct StreamPipe {
+    sender: mpsc::Sender<Result<WebSocketPacket, WebSocketError>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    handler: Arc<Mutex<HandlerSlot>>,
+}
+
+impl StreamPipe {
+    fn send(
+        &self,
+        item: Result<WebSocketPacket, WebSocketError>,
+    ) -> Result<(), mpsc::SendError<Result<WebSocketPacket, WebSocketError>>> {
+        let (handler, generation) = {
+            let mut slot = self.handler.lock().unwrap();
+            (slot.handler.take(), slot.generation)
+        };
+        if let Some(mut call) = handler {
+            // A handler that panics is thrown away, and the stream gets what arrives after
+            // that, rather than the panic taking the poll thread down.
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| call(item)));
+            if result.is_err() {
+                println!("websocket: a packet handler panicked, and has been removed");
+                return Ok(());
+            }
+            let mut slot = self.handler.lock().unwrap();
+            if slot.generation == generation {
+                slot.handler = Some(call);
+            }
+            return Ok(());
+        }
+
+        let result = self.sender.send(item);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        result
+    }
+}
+
+/// Create the pipe that carries packets to a stream.
+fn stream_pipe() -> (
+    StreamPipe,
+    mpsc::Receiver<Result<WebSocketPacket, WebSocketError>>,
+) {
+    let (sender, receiver) = mpsc::channel();
+    let pipe = StreamPipe {
+        sender,
+        waker: Arc::new(Mutex::new(None)),
+        handler: Arc::new(Mutex::new(HandlerSlot::default())),
+    };
+    (pipe, receiver)
+}
+
+/// Where the poll thread sends whatever arrives for a `WebSocketFd`.
+#[derive(Clone)]
+enum WebSocketReceiver {
+    /// Packets for a connection, and how to reopen it if it has a `ReconnectPolicy`.
+    Stream(StreamPipe, Option<Arc<Redial>>),
+    /// Connections that a listener has accepted, along with the listener's way of choosing
+    /// subprotocols.
+    Listener(
+        mpsc::Sender<Result<WebSocketStream, WebSocketError>>,
+        Option<ProtocolSelector>,
+    ),
+}
+
+impl WebSocketReceiver {
+    fn send_error(&self, e: WebSocketError) {
+        match self {
+            WebSocketReceiver::Stream(pipe, _) => pipe.send(Err(e)).is_ok(),
+            WebSocketReceiver::Listener(pipe, _) => pipe.send(Err(e)).is_ok(),
+        };
+    }
+}
+
+/// Where the poll thread sends what arrives, by connection or listener.
+#[derive(Default)]
+struct Receivers {
+    by_fd: HashMap<WebSocketFd, WebSocketReceiver>,
+    /// The number of `Open` requests that are waiting for the server. A new connection can
+    /// start receiving before its fd has been returned, and so before it's registered.
+    opening: usize,
+    /// What arrived for unknown fds while an `Open` was under way, kept until the new
+    /// connection is registered.
+    parked: HashMap<WebSocketFd, Vec<Result<WebSocketPacket, WebSocketError>>>,
+}
+
+impl Receivers {
+    /// Keep `message` for `fd`, which has no receiver, in case it belongs to a connection
+    /// that's still being opened. Returns `false` if nothing is being opened, in which case
+    /// the message is dropped.
+    fn park(&mut self, fd: WebSocketFd, message: Result<WebSocketPacket, WebSocketError>) -> bool {
+        if self.opening == 0 {
+            return false;
+        }
+        self.parked.entry(fd).or_default().push(message);
+        true
+    }
+
+    /// Note that an `Open` has been answered. Once none are left, anything still parked was
+    /// for connections that had already been dropped.
+    fn end_open(&mut self) {
+        self.opening -= 1;
+        if self.opening == 0 {
+            self.parked.clear();
+        }
+    }
+}
+
+/// Register `receiver` for `fd`, which an `Open` has just returned, once `pipe` has been
+/// given whatever arrived for it in the meantime. The lock isn't held while that's passed
+/// on, since it may be handed to a handler, so the poll thread keeps parking until nothing
+/// is left. If the connection has already closed, it isn't registered.
+fn register_opened(
+    receivers: &Mutex<Receivers>,
+    fd: WebSocketFd,
+    pipe: &StreamPipe,
+    receiver: WebSocketReceiver,
+) {
+    let mut closed = false;
+    loop {
+        let mut table = receivers.lock().unwrap();
+        let parked = table.parked.remove(&fd).unwrap_or_default();
+        if parked.is_empty() || closed {
+            table.end_open();
+            if !closed {
+                table.by_fd.insert(fd, receiver);
+            }
+            return;
+        }
+        drop(table);
+        for message in parked {
+            closed |= matches!(message, Err(WebSocketError::Closed { .. }));
+            pipe.send(message).ok();
+        }
+    }
 }
 
 /// A packet that has been received from the Xous Websocket Server
 pub struct WebSocketPacket {
     backing: xous::MemoryRange,
     valid: usize,
+    kind: MessageKind,
+    /// The pool that `backing` came from, if any. The page is returned there when the
+    /// packet is dropped, rather than being unmapped.
+    pool: Option<Arc<PagePool>>,
 }
 
 impl WebSocketPacket {
@@ -23,114 +226,1559 @@ impl WebSocketPacket {
         let valid = backing
             .len()
             .min(valid.map(|v| v.get()).unwrap_or_default());
-        WebSocketPacket { backing, valid }
+        WebSocketPacket {
+            backing,
+            valid,
+            kind: MessageKind::Binary,
+            pool: None,
+        }
+    }
+
+    /// Create a packet whose backing page is returned to `pool` when it's dropped.
+    fn from_pool(
+        backing: xous::MemoryRange,
+        valid: Option<xous::MemorySize>,
+        kind: MessageKind,
+        pool: &Arc<PagePool>,
+    ) -> WebSocketPacket {
+        WebSocketPacket {
+            kind,
+            pool: Some(pool.clone()),
+            ..WebSocketPacket::new(backing, valid)
+        }
+    }
+
+    /// The kind of message this is. Packets created with `new()` are `Binary`.
+    pub fn kind(&self) -> MessageKind {
+        self.kind
     }
 
     pub fn len(&self) -> usize {
         self.valid
     }
 
-    /// Return a slice over the internal buffer. The slice will be of a type that you specify,
-    /// for example:
-    ///     let x: &[u8] = packet1.as_slice();
-    pub fn as_slice<T>(&self) -> &[T] {
-        unsafe {
-            core::slice::from_raw_parts(
-                self.backing.as_ptr() as *const T,
-                self.len() / core::mem::size_of::<T>(),
-            )
-        }
+    pub fn is_empty(&self) -> bool {
+        self.valid == 0
     }
 
-    pub fn as_slice_mut<T>(&mut self) -> &mut [T] {
-        unsafe {
-            core::slice::from_raw_parts_mut(
-                self.backing.as_mut_ptr() as *mut T,
-                self.len() / core::mem::size_of::<T>(),
-            )
+    /// Return the contents as a `&str`, or `WebSocketError::InvalidUtf8` if they aren't
+    /// valid UTF-8. The server has already checked text messages, but this works on any
+    /// kind of packet.
+    pub fn as_str(&self) -> Result<&str, WebSocketError> {
+        std::str::from_utf8(self.as_bytes()).map_err(|_| WebSocketError::InvalidUtf8)
+    }
+
+    /// Copy the contents into a `String`, or return `WebSocketError::InvalidUtf8` if they
+    /// aren't valid UTF-8. The packet's memory is released afterwards.
+    pub fn into_string(self) -> Result<String, WebSocketError> {
+        self.as_str().map(|s| s.to_owned())
+    }
+
+    /// Return the contents as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: any bit pattern is a valid `u8`, and `valid` never exceeds the backing.
+        unsafe { &self.backing.as_slice::<u8>()[..self.valid] }
+    }
+
+    /// Return the contents as mutable bytes.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // Safety: any bit pattern is a valid `u8`, and `valid` never exceeds the backing.
+        unsafe { &mut self.backing.as_slice_mut::<u8>()[..self.valid] }
+    }
+
+    /// Return a view of the contents as a slice of `T`, without copying. For example:
+    ///     let x: &[u32] = packet1.as_slice()?;
+    /// The length must be a multiple of `size_of::<T>()` and the buffer must be aligned for
+    /// `T`, otherwise this returns `WebSocketError::InvalidLayout`.
+    pub fn as_slice<T: Pod>(&self) -> Result<&[T], WebSocketError> {
+        let len = self.typed_len::<T>()?;
+        // Safety: `typed_len()` checked the size and alignment, and `Pod` guarantees that
+        // every bit pattern is a valid `T`.
+        Ok(unsafe { core::slice::from_raw_parts(self.backing.as_ptr() as *const T, len) })
+    }
+
+    /// Return a mutable view of the contents as a slice of `T`, with the same checks as
+    /// `as_slice()`.
+    pub fn as_slice_mut<T: Pod>(&mut self) -> Result<&mut [T], WebSocketError> {
+        let len = self.typed_len::<T>()?;
+        // Safety: as in `as_slice()`. `&mut self` ensures the view is unique.
+        Ok(unsafe { core::slice::from_raw_parts_mut(self.backing.as_mut_ptr() as *mut T, len) })
+    }
+
+    /// The number of `T` in the packet, if the packet can be viewed as a slice of `T`.
+    fn typed_len<T: Pod>(&self) -> Result<usize, WebSocketError> {
+        let size = core::mem::size_of::<T>();
+        if size == 0
+            || !self.valid.is_multiple_of(size)
+            || !(self.backing.as_ptr() as *const T).is_aligned()
+        {
+            return Err(WebSocketError::InvalidLayout);
         }
+        Ok(self.valid / size)
     }
 }
 
-/// Unmap the backing store when this packet goes out of scope.
+/// Return the backing store to its pool, or unmap it, when this packet goes out of scope.
 impl Drop for WebSocketPacket {
     fn drop(&mut self) {
-        xous::unmap_memory(self.backing).expect("couldn't free memory");
+        match &self.pool {
+            Some(pool) => pool.put(self.backing),
+            // There's no way to report a failure from here, and panicking in `drop()`
+            // would be worse than leaking the page.
+            None => {
+                if let Err(e) = xous::unmap_memory(self.backing) {
+                    println!("websocket: couldn't free packet memory: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Decode the payload of a close notification, which is a big-endian close code followed
+/// by the reason. A missing code is reported as `NO_STATUS`.
+fn parse_close_payload(packet: &WebSocketPacket) -> (u16, String) {
+    match packet.as_bytes() {
+        [hi, lo, reason @ ..] => (
+            u16::from_be_bytes([*hi, *lo]),
+            String::from_utf8_lossy(reason).into_owned(),
+        ),
+        _ => (api::close_code::NO_STATUS, String::new()),
+    }
+}
+
+/// Lend `data` to the server using `opcode`. The server responds with a value in `offset`,
+/// or an error code in `valid`.
+fn lend_to_server(
+    cid: xous::CID,
+    opcode: api::Opcodes,
+    offset: usize,
+    data: &[u8],
+) -> Result<usize, WebSocketError> {
+    // Memory can only be lent in whole pages, so round up. An empty message still
+    // needs a page to lend.
+    let size = data.len().max(1).div_ceil(4096) * 4096;
+    let mut buffer = xous::map_memory(
+        None,
+        None,
+        size,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )?;
+    // Safety: any bit pattern is a valid `u8`.
+    unsafe { buffer.as_slice_mut::<u8>()[..data.len()].copy_from_slice(data) };
+
+    let msg = xous::Message::new_lend(
+        opcode as usize,
+        buffer,
+        xous::MemoryAddress::new(offset),
+        xous::MemorySize::new(data.len()),
+    );
+    let response = xous::send_message(cid, msg);
+    xous::unmap_memory(buffer)?;
+
+    match response? {
+        xous::Result::MemoryReturned(_, Some(code)) => Err(WebSocketError::from_code(code.get())),
+        xous::Result::MemoryReturned(value, None) => Ok(value.map(|v| v.get()).unwrap_or(0)),
+        _ => Err(WebSocketError::UnexpectedResponse),
     }
 }
 
+/// Messages that were too large for a single `Poll` response, and are being put back
+/// together as the rest of their pieces arrive.
+struct Reassembly {
+    max_message_size: usize,
+    /// The pieces received so far for each connection. `None` means the message grew
+    /// larger than `max_message_size`, and the rest of it is being thrown away.
+    partial: HashMap<WebSocketFd, Option<Vec<u8>>>,
+}
+
+impl Reassembly {
+    fn new(max_message_size: usize) -> Reassembly {
+        Reassembly {
+            max_message_size,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Add a piece of a message for `fd`. `more` is set if further pieces follow. This
+    /// returns `None` until there's something to pass on to the stream, which is either
+    /// the complete message or an error saying that it was too large.
+    fn push(
+        &mut self,
+        fd: WebSocketFd,
+        packet: WebSocketPacket,
+        more: bool,
+    ) -> Option<Result<WebSocketPacket, WebSocketError>> {
+        // Most messages fit in a single page, and can be passed on without copying. Control
+        // frames always fit, and may arrive in the middle of a fragmented message.
+        if packet.kind().is_control() || (!more && !self.partial.contains_key(&fd)) {
+            if packet.len() > self.max_message_size {
+                return Some(Err(WebSocketError::MessageTooLarge));
+            }
+            return Some(Ok(packet));
+        }
+
+        let mut result = None;
+        let entry = self.partial.entry(fd).or_insert_with(|| Some(Vec::new()));
+        if let Some(data) = entry {
+            if data.len() + packet.len() > self.max_message_size {
+                *entry = None;
+                result = Some(Err(WebSocketError::MessageTooLarge));
+            } else {
+                data.extend_from_slice(packet.as_bytes());
+            }
+        }
+        // The contents have been copied out, so the page can go back to the pool. Every
+        // piece carries the kind, so it's fine to take it from the last one.
+        let kind = packet.kind();
+        drop(packet);
+
+        if !more {
+            if let Some(Some(data)) = self.partial.remove(&fd) {
+                result = Some(packet_from_bytes(kind, &data));
+            }
+        }
+        result
+    }
+
+    /// Throw away any partial message for `fd`, for example because it has closed.
+    fn discard(&mut self, fd: WebSocketFd) {
+        self.partial.remove(&fd);
+    }
+}
+
+/// Copy `data` into newly-mapped pages, so that it can be handed out as a single packet.
+fn packet_from_bytes(kind: MessageKind, data: &[u8]) -> Result<WebSocketPacket, WebSocketError> {
+    let size = data.len().max(1).div_ceil(pool::PAGE_SIZE) * pool::PAGE_SIZE;
+    let mut backing = xous::map_memory(
+        None,
+        None,
+        size,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )?;
+    // Safety: any bit pattern is a valid `u8`.
+    unsafe { backing.as_slice_mut::<u8>()[..data.len()].copy_from_slice(data) };
+    let mut packet = WebSocketPacket::new(backing, xous::MemorySize::new(data.len()));
+    packet.kind = kind;
+    Ok(packet)
+}
+
 /// A thread that lives inside a process to poll websocket connections. It calls `Poll` on
 /// the websocket server and passes it a buffer. When data is available, this buffer will
 /// be returned filled with data. The amount of data that is available will be in the `valid`
-/// slot.
+/// slot. Messages that don't fit in one buffer arrive in pieces, and are reassembled here
+/// before being passed on.
 fn websocket_poll_thread(
     websocket_server_cid: xous::CID,
-    receivers: Arc<Mutex<HashMap<WebSocketFd, WebSocketReceiver>>>,
+    receivers: Arc<Mutex<Receivers>>,
+    pool: Arc<PagePool>,
+    max_message_size: usize,
 ) {
+    let mut reassembly = Reassembly::new(max_message_size);
+    // Set while no buffer can be had, so that's only reported once.
+    let mut out_of_memory = false;
     loop {
-        // Allocate a new buffer to pass to this thread. This memory is managed by us, and
-        // will need to be freed with `xous::unmap_memory(buffer)`.
-        let buffer = xous::map_memory(
-            None,
-            None,
-            4096,
-            xous::MemoryFlags::R | xous::MemoryFlags::W,
-        )
-        .expect("out of memory");
+        // Take a buffer from the pool to pass to the server. This memory is managed by us,
+        // and will need to be handed back with `pool.put(buffer)` when we're done with it.
+        let buffer = match pool.get() {
+            Ok(buffer) => {
+                out_of_memory = false;
+                buffer
+            }
+            Err(e) => {
+                // Nothing can be received without a buffer, but nothing is lost either,
+                // since the server holds on to whatever arrives in the meantime. Give the
+                // rest of the system a moment to free some memory up, and try again.
+                if !out_of_memory {
+                    println!("websocket: couldn't get a receive buffer: {:?}", e);
+                    out_of_memory = true;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                continue;
+            }
+        };
 
         // Create the `Poll` message with no additional arguments (i.e. `Offset` and `Valid` set to None)
         let msg = xous::Message::new_lend_mut(api::Opcodes::Poll as usize, buffer, None, None);
 
         // Send the `Poll` message to the websocket_cid. Because this is a `lend_mut`, this will
         // block forever until the server shuts down or responds.
-        let response = xous::send_message(websocket_server_cid, msg).expect("couldn't send");
+        let response = match xous::send_message(websocket_server_cid, msg) {
+            Ok(response) => response,
+            Err(e) => {
+                // The server is unreachable, so nothing more will arrive on any stream. Pass
+                // the error on, and drop every receiver so that later calls to `recv()`
+                // return `ConnectionClosed`.
+                pool.put(buffer);
+                let all: Vec<(WebSocketFd, WebSocketReceiver)> =
+                    receivers.lock().unwrap().by_fd.drain().collect();
+                for (_, receiver) in all {
+                    receiver.send_error(copy_xous_error(&e));
+                }
+                return;
+            }
+        };
 
         // When memory is returned, there are two `usize` of information that are attached
         // to the response. These are currently called `offset` and `valid`, however they
         // are actually arbitrary information and may be reused as user values. I really want
         // to change this inside libxous, since it's completely not obvious.
-        if let xous::Result::MemoryReturned(offset, valid) = response {
-            // `offset` is an `Option<NonZeroUSize>`, so turn it into a normal `usize`.
-            // One of the many warts that I would like to fix in a v2 of the library.
-            let target_fd = WebSocketFd(offset.map(|o| o.get()).unwrap_or(0).try_into().unwrap());
-
-            // Send the websocket to the Channel that's waiting to receive it. This will transfer ownership
-            // of the data there, so it's up to that thread to free the message.
-            if let Some(receiver) = receivers.lock().unwrap().get(&target_fd) {
-                if let Ok(()) = receiver.pipe.send(WebSocketPacket::new(buffer, valid)) {
-                    // The message was successfully transferred, loop around.
-                    continue;
+        let xous::Result::MemoryReturned(offset, valid) = response else {
+            pool.put(buffer);
+            continue;
+        };
+        // `offset` is an `Option<NonZeroUSize>`, so turn it into a normal `usize`.
+        // One of the many warts that I would like to fix in a v2 of the library.
+        let (target_fd, kind, flags) = api::parse_poll_offset(offset.map(|o| o.get()).unwrap_or(0));
+        let target_fd = WebSocketFd(target_fd);
+
+        // The server is going away, or has let go of this process after `shutdown()`.
+        // Everything has been closed, and nothing more will arrive.
+        if flags & api::POLL_FLAG_SHUTDOWN != 0 {
+            pool.put(buffer);
+            let all: Vec<(WebSocketFd, WebSocketReceiver)> =
+                receivers.lock().unwrap().by_fd.drain().collect();
+            for (_, receiver) in all {
+                receiver.send_error(WebSocketError::Closed {
+                    code: api::close_code::GOING_AWAY,
+                    reason: String::new(),
+                });
+            }
+            return;
+        }
+
+        let Some(kind) = kind else {
+            println!("Error: got a message of a kind that doesn't exist!");
+            pool.put(buffer);
+            continue;
+        };
+
+        // From here on, `packet` owns the buffer and returns it to the pool when dropped.
+        let packet = WebSocketPacket::from_pool(buffer, valid, kind, &pool);
+
+        // The connection has closed. Pass the close code and reason on to the stream,
+        // and remove the receiver since nothing more will arrive for this fd. If the
+        // stream has already been dropped there's nobody left to tell, unless it's still
+        // being opened. A stream with a `ReconnectPolicy` whose connection dropped is
+        // reopened instead, which can take a while, so that happens on its own thread.
+        if flags & api::POLL_FLAG_CLOSED != 0 {
+            reassembly.discard(target_fd);
+            let (code, reason) = parse_close_payload(&packet);
+            let closed = WebSocketError::Closed { code, reason };
+            let mut table = receivers.lock().unwrap();
+            let Some(receiver) = table.by_fd.remove(&target_fd) else {
+                table.park(target_fd, Err(closed));
+                continue;
+            };
+            drop(table);
+            match receiver {
+                WebSocketReceiver::Stream(pipe, Some(redial))
+                    if code == api::close_code::ABNORMAL && !redial.link.lock().unwrap().closed =>
+                {
+                    let receivers = receivers.clone();
+                    let failed = pipe.clone();
+                    let spawned = std::thread::Builder::new()
+                        .name("websocket reconnect".to_owned())
+                        .spawn(move || {
+                            reconnect_thread(websocket_server_cid, receivers, pipe, redial, closed)
+                        });
+                    if spawned.is_err() {
+                        failed.send(Err(WebSocketError::ThreadSpawnFailed)).ok();
+                    }
                 }
-            } else {
+                receiver => receiver.send_error(closed),
+            }
+            continue;
+        }
+
+        // Anything that didn't fit in one page arrives in pieces, including the reports for
+        // listeners, and only the last piece carries the flags.
+        let more = flags & api::POLL_FLAG_MORE != 0;
+        let Some(message) = reassembly.push(target_fd, packet, more) else {
+            continue;
+        };
+        // Something for an fd that isn't known may be for a connection that's still being
+        // opened. The lookup and parking happen under one lock, so that nothing is parked
+        // after the new connection has collected what's waiting for it.
+        let mut table = receivers.lock().unwrap();
+        let receiver = table.by_fd.get(&target_fd).cloned();
+        if receiver.is_none() && flags & api::POLL_FLAG_SELECT == 0 {
+            if !table.park(target_fd, message) {
                 println!("Error: got a message for a WebSocketFd that doesn't exist!");
             }
+            continue;
+        }
+        drop(table);
+
+        // An incoming connection is waiting for the listener's owner to choose one of the
+        // subprotocols it offered. The answer has to go back even if nothing is chosen, so
+        // that the handshake can carry on. If the offers were too large to keep, there's
+        // no fd to answer, and the handshake gives up waiting by itself.
+        if flags & api::POLL_FLAG_SELECT != 0 {
+            if let Ok([hi, lo, offers @ ..]) = message.as_ref().map(|p| p.as_bytes()) {
+                let offers = std::str::from_utf8(offers).unwrap_or_default();
+                let offers: Vec<&str> = http::split_list(offers).collect();
+                // A selector that panics chooses nothing, rather than taking the poll thread
+                // down with it.
+                let protocol = match receiver {
+                    Some(WebSocketReceiver::Listener(_, Some(select))) => {
+                        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| select(&offers)))
+                            .unwrap_or_else(|_| {
+                                println!("websocket: a protocol selector panicked");
+                                None
+                            })
+                    }
+                    _ => None,
+                };
+                lend_to_server(
+                    websocket_server_cid,
+                    api::Opcodes::Select,
+                    u16::from_be_bytes([*hi, *lo]) as usize,
+                    protocol.unwrap_or_default().as_bytes(),
+                )
+                .ok();
+            }
+            continue;
+        }
+        let Some(receiver) = receiver else {
+            continue;
+        };
+
+        // There's no point in receiving the rest of a message that's going to be thrown
+        // away, so ask the server to close the connection. Listeners are left alone, since
+        // what's too large there is a remote client's request rather than the listener.
+        if let (WebSocketReceiver::Stream(..), Err(WebSocketError::MessageTooLarge)) =
+            (&receiver, &message)
+        {
+            lend_to_server(
+                websocket_server_cid,
+                api::Opcodes::Close,
+                api::close_offset(target_fd.0, api::close_code::MESSAGE_TOO_BIG),
+                b"",
+            )
+            .ok();
+        }
+
+        // Send the websocket to the Channel that's waiting to receive it. This will transfer ownership
+        // of the data there, so it's up to that thread to free the message. If the stream has gone
+        // away, the packet is handed back in the error and freed when it's dropped. The lock is
+        // released first, since dropping a stream that couldn't be delivered takes it again.
+        match receiver {
+            WebSocketReceiver::Stream(pipe, _) => {
+                pipe.send(message).ok();
+            }
+            WebSocketReceiver::Listener(pipe, _) => {
+                if flags & api::POLL_FLAG_ACCEPTED != 0 || message.is_err() {
+                    let stream = message.and_then(|packet| {
+                        accepted_stream(websocket_server_cid, &receivers, &packet)
+                    });
+                    // If the listener has been dropped then so is the stream, which closes it.
+                    pipe.send(stream).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Register a connection that a listener accepted, and create a stream for it. The packet
+/// holds the new `WebSocketFd`, followed by the client's request and the chosen subprotocol.
+fn accepted_stream(
+    cid: xous::CID,
+    receivers: &Arc<Mutex<Receivers>>,
+    packet: &WebSocketPacket,
+) -> Result<WebSocketStream, WebSocketError> {
+    let [hi, lo, request @ ..] = packet.as_bytes() else {
+        return Err(WebSocketError::UnexpectedResponse);
+    };
+    let fd = WebSocketFd(u16::from_be_bytes([*hi, *lo]));
+    let (request, protocol) = request.split_at(http::head_len(request).unwrap_or(request.len()));
+    let protocol = std::str::from_utf8(protocol).unwrap_or_default();
+    let (pipe, receiver) = stream_pipe();
+    let waker = pipe.waker.clone();
+    let handler = pipe.handler.clone();
+    receivers
+        .lock()
+        .unwrap()
+        .by_fd
+        .insert(fd, WebSocketReceiver::Stream(pipe, None));
+    Ok(WebSocketStream {
+        link: Arc::new(Mutex::new(Link { fd, closed: false })),
+        cid,
+        response: HttpHead::parse(request).unwrap_or_default(),
+        protocol: Some(protocol.to_owned()).filter(|p| !p.is_empty()),
+        waker,
+        handler,
+        receiver,
+        #[cfg(feature = "async")]
+        sink: None,
+        receivers: receivers.clone(),
+    })
+}
+
+/// Reopen a stream's connection after it dropped, following its `ReconnectPolicy`. Each
+/// attempt is announced to the stream with `WebSocketError::Reconnecting`, and success with
+/// `WebSocketError::Reconnected`. If every attempt fails, the stream gets `closed`, which
+/// says how the connection dropped. This gives up quietly if the stream is closed or
+/// dropped in the meantime.
+fn reconnect_thread(
+    cid: xous::CID,
+    receivers: Arc<Mutex<Receivers>>,
+    pipe: StreamPipe,
+    redial: Arc<Redial>,
+    closed: WebSocketError,
+) {
+    for attempt in 1..=redial.policy.max_attempts {
+        let delay = redial.policy.delay(attempt);
+        if redial.link.lock().unwrap().closed
+            || pipe
+                .send(Err(WebSocketError::Reconnecting { attempt, delay }))
+                .is_err()
+        {
+            return;
+        }
+        std::thread::sleep(delay);
+
+        // As in `open()`, anything that arrives before the new receiver is registered is
+        // parked until it is.
+        receivers.lock().unwrap().opening += 1;
+        let Ok((fd, head)) = send_open(cid, &redial.request, redial.offset) else {
+            receivers.lock().unwrap().end_open();
+            continue;
+        };
+        let mut link = redial.link.lock().unwrap();
+        let stream_closed = link.closed;
+        if stream_closed || head.header("Sec-WebSocket-Protocol") != redial.protocol.as_deref() {
+            drop(link);
+            receivers.lock().unwrap().end_open();
+            lend_to_server(
+                cid,
+                api::Opcodes::Close,
+                api::close_offset(fd.0, api::close_code::NORMAL),
+                b"",
+            )
+            .ok();
+            if stream_closed {
+                return;
+            }
+            continue;
+        }
+        link.fd = fd;
+        drop(link);
+        // This goes ahead of anything that arrived on the new connection.
+        pipe.send(Err(WebSocketError::Reconnected)).ok();
+        let receiver = WebSocketReceiver::Stream(pipe.clone(), Some(redial.clone()));
+        register_opened(&receivers, fd, &pipe, receiver);
+        return;
+    }
+    pipe.send(Err(closed)).ok();
+}
+
+/// Send an `Open` request, which holds the URL and everything that follows it, and wait for
+/// the opening handshake to finish. Returns the new connection and the server's response.
+fn send_open(
+    cid: xous::CID,
+    request: &[u8],
+    offset: usize,
+) -> Result<(WebSocketFd, HttpHead), WebSocketError> {
+    // Copy the request into pages that can be lent to the server. There's always at
+    // least one page, which is where the server's response goes.
+    let mut buffer = xous::map_memory(
+        None,
+        None,
+        request.len().max(1).div_ceil(4096) * 4096,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )?;
+    // Safety: any bit pattern is a valid `u8`.
+    unsafe { buffer.as_slice_mut::<u8>()[..request.len()].copy_from_slice(request) };
+
+    let msg = xous::Message::new_lend_mut(
+        api::Opcodes::Open as usize,
+        buffer,
+        xous::MemorySize::new(offset),
+        xous::MemorySize::new(request.len()),
+    );
+    let response = xous::send_message(cid, msg);
+    // The server wrote the handshake response into the page, ending with zeroes.
+    // Safety: any bit pattern is a valid `u8`.
+    let head = unsafe { buffer.as_slice::<u8>() };
+    let head_len = head.iter().position(|&b| b == 0).unwrap_or(head.len());
+    let head = HttpHead::parse(&head[..head_len]).unwrap_or_default();
+    xous::unmap_memory(buffer)?;
+
+    // The server puts the new `WebSocketFd` in `offset` on success, or an error code
+    // in `valid` on failure.
+    match response? {
+        xous::Result::MemoryReturned(status, Some(code)) => {
+            Err(match api::Error::from_usize(code.get()) {
+                Some(api::Error::BadStatus) => WebSocketError::BadStatus {
+                    status: status.map(|s| s.get() as u16).unwrap_or_default(),
+                    response: head,
+                },
+                _ => WebSocketError::from_code(code.get()),
+            })
+        }
+        xous::Result::MemoryReturned(Some(fd), None) => fd
+            .get()
+            .try_into()
+            .map(|fd| (WebSocketFd(fd), head))
+            .map_err(|_| WebSocketError::UnexpectedResponse),
+        _ => Err(WebSocketError::UnexpectedResponse),
+    }
+}
+
+/// `xous::Error` isn't `Clone`, so this makes a copy by round-tripping it through `usize`.
+fn copy_xous_error(e: &xous::Error) -> WebSocketError {
+    WebSocketError::Xous(xous::Error::from_usize(e.to_usize()))
+}
+
+/// Settings for `WebSocketService::new_with_config()`.
+#[derive(Clone, Debug)]
+pub struct ServiceConfig {
+    /// The name that the websocket server registered with xous-names.
+    pub server_name: String,
+    /// The number of receive pages to keep on hand. These are mapped when the service is
+    /// created, and the pool is trimmed back down to this many when it grows too large.
+    pub pool_low_watermark: usize,
+    /// The most receive pages to keep on hand before trimming the pool.
+    pub pool_high_watermark: usize,
+    /// The largest message that will be reassembled, in bytes. Larger messages are
+    /// reported as `WebSocketError::MessageTooLarge`, and the connection is closed.
+    pub max_message_size: usize,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            server_name: api::SERVER_NAME.to_owned(),
+            pool_low_watermark: 4,
+            pool_high_watermark: 16,
+            max_message_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Per-connection settings for `WebSocketService::open_with_options()` and
+/// `WebSocketService::listen_with_options()`.
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    /// Negotiate `permessage-deflate` compression if the other side supports it.
+    pub compression: bool,
+    /// The subprotocols to request, in order of preference. For a listener, these are the
+    /// subprotocols it supports, and the first one that a client offers is chosen.
+    pub protocols: Vec<String>,
+    /// DER-encoded root certificates to trust for `wss://` connections, for example to
+    /// reach a server with a private certificate authority.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Trust the built-in root certificates, which are Mozilla's, as well as
+    /// `root_certificates`.
+    pub builtin_roots: bool,
+    /// Extra headers to send with the upgrade request, such as `Authorization`. They're
+    /// sent as they are, but can't replace the headers that the handshake sets itself or
+    /// hop-by-hop headers. Use `protocols` rather than `Sec-WebSocket-Protocol`.
+    pub headers: Vec<(String, String)>,
+    /// Cookies to send with the upgrade request, as names and values. These are sent in a
+    /// single `Cookie` header.
+    pub cookies: Vec<(String, String)>,
+    /// How long the connection may be idle before the server pings it, rounded to whole
+    /// seconds. `None` turns keepalive pings off.
+    pub ping_interval: Option<Duration>,
+    /// How many keepalive pings may go unanswered in a row before the connection is
+    /// closed with `api::close_code::ABNORMAL` and `api::TIMEOUT_REASON`.
+    pub max_missed_pongs: u8,
+    /// Reopen the connection if it drops, rather than closing the stream. This doesn't
+    /// apply to listeners.
+    pub reconnect: Option<ReconnectPolicy>,
+}
 
-            // There was an error sending the message. Free the buffer and try again.
-            xous::unmap_memory(buffer).expect("couldn't free memory");
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            compression: true,
+            protocols: Vec::new(),
+            root_certificates: Vec::new(),
+            builtin_roots: true,
+            headers: Vec::new(),
+            cookies: Vec::new(),
+            ping_interval: Some(Duration::from_secs(30)),
+            max_missed_pongs: 3,
+            reconnect: None,
         }
     }
 }
 
+/// How to reopen a connection that dropped, for `OpenOptions::reconnect`. A connection
+/// dropped if it closed with `api::close_code::ABNORMAL`, for example because the network
+/// went away or keepalive pings went unanswered. The same URL, headers and other options
+/// are used again, and the `WebSocketStream` carries on as before once it's reopened.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// How many times to try reopening the connection each time it drops. If they all
+    /// fail, the stream is closed.
+    pub max_attempts: u32,
+    /// How long to wait before the first attempt. The wait doubles after each failure.
+    pub base_delay: Duration,
+    /// The longest to wait between attempts.
+    pub max_delay: Duration,
+    /// How much of each wait may be taken off at random, from 0.0 to 1.0. This keeps
+    /// devices that dropped together from all coming back at once. Values outside that
+    /// range are clamped to it, except that NaN and infinities are taken as 0.0.
+    pub jitter: f32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before attempt number `attempt`, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        // `RandomState` is seeded randomly, which is all the randomness that's needed here.
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        // `clamp()` passes NaN through, and `mul_f64()` panics on it.
+        let jitter = if self.jitter.is_finite() {
+            self.jitter.clamp(0.0, 1.0) as f64
+        } else {
+            0.0
+        };
+        delay.mul_f64(1.0 - jitter * random)
+    }
+}
+
+impl OpenOptions {
+    /// The value built by `api::open_offset()` that describes these options.
+    fn open_offset(&self) -> usize {
+        let mut flags = 0;
+        if !self.compression {
+            flags |= api::OPEN_FLAG_NO_COMPRESSION;
+        }
+        if !self.builtin_roots {
+            flags |= api::OPEN_FLAG_NO_BUILTIN_ROOTS;
+        }
+        let ping_interval = self
+            .ping_interval
+            .map(|interval| interval.as_secs().clamp(1, u16::MAX as u64) as u16)
+            .unwrap_or(0);
+        api::open_offset(flags, ping_interval, self.max_missed_pongs)
+    }
+
+    /// Check the extra headers and cookies, and return every header that goes in the
+    /// `Open` request, including the one that lists the subprotocols.
+    fn request_headers(&self) -> Result<Vec<(String, String)>, WebSocketError> {
+        let mut headers = Vec::new();
+        for (name, value) in &self.headers {
+            if !http::is_token(name) || !http::is_header_value(value) {
+                return Err(WebSocketError::InvalidHeader);
+            }
+            if http::is_reserved_header(name) || name.eq_ignore_ascii_case("Sec-WebSocket-Protocol")
+            {
+                return Err(WebSocketError::ForbiddenHeader);
+            }
+            headers.push((name.clone(), value.clone()));
+        }
+        if !self.cookies.is_empty() {
+            // RFC 6265 section 4.2.1 doesn't allow separators in cookie values.
+            let valid = |value: &str| {
+                value
+                    .bytes()
+                    .all(|b| b.is_ascii_graphic() && !b"\",;\\".contains(&b))
+            };
+            if !self
+                .cookies
+                .iter()
+                .all(|(name, value)| http::is_token(name) && valid(value))
+            {
+                return Err(WebSocketError::InvalidHeader);
+            }
+            let cookies: Vec<String> = self
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            headers.push(("Cookie".to_owned(), cookies.join("; ")));
+        }
+        if !self.protocols.is_empty() {
+            if !self.protocols.iter().all(|p| http::is_token(p)) {
+                return Err(WebSocketError::InvalidProtocol);
+            }
+            headers.push((
+                "Sec-WebSocket-Protocol".to_owned(),
+                self.protocols.join(", "),
+            ));
+        }
+        let size: usize = headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len() + 4)
+            .sum();
+        if size > api::MAX_REQUEST_HEADERS {
+            return Err(WebSocketError::HeadersTooLarge);
+        }
+        Ok(headers)
+    }
+}
+
 #[derive(Clone)]
 pub struct WebSocketService {
-    receivers: Arc<Mutex<HashMap<WebSocketFd, WebSocketReceiver>>>,
+    receivers: Arc<Mutex<Receivers>>,
     cid: xous::CID,
+    /// Cleared by `shutdown()`, after which nothing can be opened.
+    running: Arc<AtomicBool>,
+    /// The poll thread, until `shutdown()` stops it.
+    poll_thread: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
 }
 
+/// The services that this process has started, with the name of the server that each one
+/// is connected to. The server hands incoming data to whichever `Poll` from a process comes
+/// in first, so a second poll thread in the same process would take data meant for the
+/// first one's streams, and `Disconnect` would close both of their connections.
+static SERVICES: Mutex<Vec<(String, WebSocketService)>> = Mutex::new(Vec::new());
+
 impl WebSocketService {
-    pub fn new() -> WebSocketService {
-        let receivers = Arc::new(Mutex::new(HashMap::new()));
-        // This should be replaced with a call to `xous-names`, instead of using a hardcoded server name.
-        let cid = xous::connect(xous::SID::from_bytes(b"~xous-websocket~").unwrap())
-            .expect("couldn't connect to websocket server");
-        {
+    /// Connect to the websocket server and start a thread to poll it for incoming data.
+    /// There is one service per server in each process, so if this process already has one
+    /// then a clone of it is returned instead.
+    pub fn new() -> Result<WebSocketService, WebSocketError> {
+        Self::new_with_name(api::SERVER_NAME)
+    }
+
+    /// Connect to a websocket server that registered with xous-names under `name`, rather
+    /// than the default name. This is mostly useful for tests, which can run their own copy
+    /// of the server.
+    pub fn new_with_name(name: &str) -> Result<WebSocketService, WebSocketError> {
+        Self::new_with_config(ServiceConfig {
+            server_name: name.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    /// Connect to the websocket server using the settings in `config`. If this process
+    /// already has a service for `config.server_name` that hasn't been shut down, a clone of
+    /// it is returned and the rest of `config` is ignored.
+    pub fn new_with_config(config: ServiceConfig) -> Result<WebSocketService, WebSocketError> {
+        loop {
+            // The lock is held while a new service starts, so that two threads can't both
+            // start one.
+            let mut services = SERVICES.lock().unwrap();
+            let Some(index) = services
+                .iter()
+                .position(|(name, _)| *name == config.server_name)
+            else {
+                let service = Self::start(&config)?;
+                services.push((config.server_name.clone(), service.clone()));
+                return Ok(service);
+            };
+            let service = services[index].1.clone();
+            if service.running.load(Ordering::SeqCst) {
+                return Ok(service);
+            }
+            // A service that was shut down from one of its handlers leaves its poll thread
+            // to exit once the handler returns. That thread's next `Poll` is the one that
+            // the server answers with `POLL_FLAG_SHUTDOWN`, so it has to be gone before
+            // another poll thread starts. From the thread itself there's nothing to wait
+            // for yet, so the stopped service is all that can be returned.
+            let handle = service.poll_thread.lock().unwrap().take();
+            match handle {
+                Some(handle) if handle.thread().id() == std::thread::current().id() => {
+                    *service.poll_thread.lock().unwrap() = Some(handle);
+                    return Ok(service);
+                }
+                Some(handle) => {
+                    // The handler may be creating a service of its own, so the lock is
+                    // released while waiting.
+                    drop(services);
+                    handle.join().ok();
+                }
+                None => {
+                    services.remove(index);
+                }
+            }
+        }
+    }
+
+    /// Start a new service, with its own connection to the server and poll thread.
+    fn start(config: &ServiceConfig) -> Result<WebSocketService, WebSocketError> {
+        let receivers = Arc::new(Mutex::new(Receivers::default()));
+        let xns = xous_names::XousNames::new()?;
+        let cid = xns.request_connection_blocking(&config.server_name)?;
+        let pool = Arc::new(PagePool::new(
+            config.pool_low_watermark,
+            config.pool_high_watermark,
+        ));
+        let poll_thread = {
             let receivers = receivers.clone();
-            std::thread::spawn(move || websocket_poll_thread(cid, receivers));
+            let max_message_size = config.max_message_size;
+            std::thread::Builder::new()
+                .name("websocket poll".to_owned())
+                .spawn(move || websocket_poll_thread(cid, receivers, pool, max_message_size))
+                .map_err(|_| WebSocketError::ThreadSpawnFailed)?
+        };
+        Ok(WebSocketService {
+            receivers,
+            cid,
+            running: Arc::new(AtomicBool::new(true)),
+            poll_thread: Arc::new(Mutex::new(Some(poll_thread))),
+        })
+    }
+
+    /// Close every connection and listener that this process has open, and stop the poll
+    /// thread, waiting for it to exit. Streams and listeners get `WebSocketError::Closed`
+    /// with `api::close_code::GOING_AWAY`. Nothing can be opened afterwards, by this
+    /// service or any of its clones, but `new()` starts a fresh service.
+    ///
+    /// This may be called from a handler, which runs on the poll thread. The thread exits
+    /// once the handler returns, rather than being waited for.
+    pub fn shutdown(&self) -> Result<(), WebSocketError> {
+        // Only the first call does anything, and another one returns straight away rather
+        // than waiting for the poll thread as well.
+        if self
+            .running
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Ok(());
+        }
+        let msg = xous::Message::new_blocking_scalar(api::Opcodes::Disconnect as usize, 0, 0, 0, 0);
+        let result = match xous::send_message(self.cid, msg) {
+            Ok(xous::Result::Scalar1(0)) => Ok(()),
+            Ok(xous::Result::Scalar1(code)) => Err(WebSocketError::from_code(code)),
+            Ok(_) => Err(WebSocketError::UnexpectedResponse),
+            Err(e) => Err(e.into()),
+        };
+        if result.is_err() {
+            // Nothing was closed, so carry on as before.
+            self.running.store(true, Ordering::SeqCst);
+            return result;
         }
-        WebSocketService { receivers, cid }
+        // The server has returned the outstanding `Poll`, or will return the next one, so
+        // the thread is on its way out. If this is that thread, the handle is left for
+        // `new_with_config()` to wait on.
+        let mut poll_thread = self.poll_thread.lock().unwrap();
+        let current = std::thread::current().id();
+        if let Some(handle) = poll_thread.take_if(|handle| handle.thread().id() != current) {
+            drop(poll_thread);
+            handle.join().ok();
+        }
+        Ok(())
+    }
+
+    /// Nothing would ever arrive on a connection opened after `shutdown()`.
+    fn check_running(&self) -> Result<(), WebSocketError> {
+        if !self.running.load(Ordering::SeqCst) {
+            return Err(WebSocketError::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    /// Open a connection to `url`, which may be `ws://` or `wss://`. This blocks until the
+    /// connection is established and the opening handshake has finished.
+    pub fn open(&self, url: &str) -> Result<WebSocketStream, WebSocketError> {
+        self.open_with_options(url, &OpenOptions::default())
+    }
+
+    /// Open a connection to `url` using the settings in `options`.
+    pub fn open_with_options(
+        &self,
+        url: &str,
+        options: &OpenOptions,
+    ) -> Result<WebSocketStream, WebSocketError> {
+        self.check_running()?;
+        if url.len() > 4096 {
+            return Err(WebSocketError::UrlTooLong);
+        }
+        // The headers follow the URL, one per line.
+        let mut request = url.to_owned();
+        for (name, value) in options.request_headers()? {
+            request += &format!("\r\n{}: {}", name, value);
+        }
+
+        // Root certificates come after a blank line, each preceded by its length.
+        let mut request = request.into_bytes();
+        if !options.root_certificates.is_empty() {
+            request.extend_from_slice(b"\r\n\r\n");
+            for der in &options.root_certificates {
+                let len =
+                    u32::try_from(der.len()).map_err(|_| WebSocketError::InvalidRootCertificate)?;
+                request.extend_from_slice(&len.to_be_bytes());
+                request.extend_from_slice(der);
+            }
+        }
+
+        // The server may start sending data as soon as it responds, before the new receiver
+        // has been inserted. The poll thread parks it until then.
+        self.receivers.lock().unwrap().opening += 1;
+        let offset = options.open_offset();
+        let (fd, head) = send_open(self.cid, &request, offset).inspect_err(|_| {
+            self.receivers.lock().unwrap().end_open();
+        })?;
+
+        let protocol = head.header("Sec-WebSocket-Protocol").map(|p| p.to_owned());
+        let link = Arc::new(Mutex::new(Link { fd, closed: false }));
+        let redial = options.reconnect.clone().map(|policy| {
+            Arc::new(Redial {
+                policy,
+                request,
+                offset,
+                protocol: protocol.clone(),
+                link: link.clone(),
+            })
+        });
+        let (pipe, receiver) = stream_pipe();
+        let waker = pipe.waker.clone();
+        let handler = pipe.handler.clone();
+        // If the connection has already closed, the stream's first `recv()` says so.
+        register_opened(
+            &self.receivers,
+            fd,
+            &pipe,
+            WebSocketReceiver::Stream(pipe.clone(), redial),
+        );
+        Ok(WebSocketStream {
+            link,
+            cid: self.cid,
+            protocol,
+            response: head,
+            waker,
+            handler,
+            receiver,
+            #[cfg(feature = "async")]
+            sink: None,
+            receivers: self.receivers.clone(),
+        })
+    }
+
+    /// Listen for websocket connections on `port`. The server accepts them and performs the
+    /// opening handshake, and they're handed out by `WebSocketListener::accept()`.
+    pub fn listen(&self, port: u16) -> Result<WebSocketListener, WebSocketError> {
+        self.listen_with_options(port, &OpenOptions::default())
+    }
+
+    /// Listen for websocket connections on `port`, applying `options` to every connection
+    /// that is accepted.
+    pub fn listen_with_options(
+        &self,
+        port: u16,
+        options: &OpenOptions,
+    ) -> Result<WebSocketListener, WebSocketError> {
+        // Without a selector the server doesn't ask, and no subprotocol is chosen.
+        let mut selector: Option<ProtocolSelector> = None;
+        if !options.protocols.is_empty() {
+            let supported = options.protocols.clone();
+            selector = Some(Arc::new(move |offers: &[&str]| {
+                offers
+                    .iter()
+                    .find(|offer| supported.iter().any(|p| p == *offer))
+                    .map(|offer| offer.to_string())
+            }));
+        }
+        self.listen_inner(port, options, selector)
+    }
+
+    /// Listen for websocket connections on `port`, and call `selector` to choose a
+    /// subprotocol whenever a client offers some. It's given the offers in the client's
+    /// order of preference, and returns one of them or `None`. `options.protocols` is
+    /// ignored.
+    ///
+    /// `selector` runs on the service's poll thread, so nothing is received on any
+    /// connection until it returns. If it panics, no subprotocol is chosen.
+    pub fn listen_with_selector<F>(
+        &self,
+        port: u16,
+        options: &OpenOptions,
+        selector: F,
+    ) -> Result<WebSocketListener, WebSocketError>
+    where
+        F: Fn(&[&str]) -> Option<String> + Send + Sync + 'static,
+    {
+        self.listen_inner(port, options, Some(Arc::new(selector)))
+    }
+
+    fn listen_inner(
+        &self,
+        port: u16,
+        options: &OpenOptions,
+        selector: Option<ProtocolSelector>,
+    ) -> Result<WebSocketListener, WebSocketError> {
+        self.check_running()?;
+        let mut offset = options.open_offset();
+        if selector.is_some() {
+            offset |= api::OPEN_FLAG_SELECT_PROTOCOL;
+        }
+
+        // Hold the lock so that accepted connections have somewhere to go. Unlike `Open`,
+        // the server answers this straight away.
+        let mut receivers = self.receivers.lock().unwrap();
+
+        let msg = xous::Message::new_blocking_scalar(
+            api::Opcodes::Listen as usize,
+            port as usize,
+            offset,
+            0,
+            0,
+        );
+        let fd = match xous::send_message(self.cid, msg)? {
+            xous::Result::Scalar2(_, code) if code != 0 => {
+                return Err(WebSocketError::from_code(code))
+            }
+            xous::Result::Scalar2(fd, _) => fd
+                .try_into()
+                .map(WebSocketFd)
+                .map_err(|_| WebSocketError::UnexpectedResponse)?,
+            _ => return Err(WebSocketError::UnexpectedResponse),
+        };
+
+        let (pipe, receiver) = mpsc::channel();
+        receivers
+            .by_fd
+            .insert(fd, WebSocketReceiver::Listener(pipe, selector));
+        Ok(WebSocketListener {
+            fd,
+            cid: self.cid,
+            receiver,
+            receivers: self.receivers.clone(),
+        })
     }
 }
 
-pub struct WebSocketStream {
+/// Accepts websocket connections on a port, created by `WebSocketService::listen()`.
+pub struct WebSocketListener {
     fd: WebSocketFd,
-}
\ No newline at end of file
+    cid: xous::CID,
+    /// Accepted connections are sent here by the poll thread.
+    receiver: mpsc::Receiver<Result<WebSocketStream, WebSocketError>>,
+    /// The service's table of receivers, so this listener can remove itself when dropped.
+    receivers: Arc<Mutex<Receivers>>,
+}
+
+impl WebSocketListener {
+    /// Wait for the next connection. Its `response()` holds the client's request. If the
+    /// listener fails, this returns `WebSocketError::Closed`, and
+    /// `WebSocketError::ConnectionClosed` after that.
+    pub fn accept(&self) -> Result<WebSocketStream, WebSocketError> {
+        self.receiver
+            .recv()
+            .map_err(|_| WebSocketError::ConnectionClosed)?
+    }
+
+    /// Return the next connection if one has already been accepted, or
+    /// `WebSocketError::WouldBlock` if there is nothing waiting.
+    pub fn try_accept(&self) -> Result<WebSocketStream, WebSocketError> {
+        self.receiver.try_recv().map_err(|e| match e {
+            mpsc::TryRecvError::Empty => WebSocketError::WouldBlock,
+            mpsc::TryRecvError::Disconnected => WebSocketError::ConnectionClosed,
+        })?
+    }
+}
+
+impl core::fmt::Debug for WebSocketListener {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WebSocketListener")
+            .field("fd", &self.fd.0)
+            .field("cid", &self.cid)
+            .finish()
+    }
+}
+
+/// Iterate over connections as they are accepted. Iteration ends if the listener fails.
+impl Iterator for WebSocketListener {
+    type Item = WebSocketStream;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.accept().ok()
+    }
+}
+
+/// Stop listening when the listener goes out of scope. Connections that were already
+/// accepted stay open.
+impl Drop for WebSocketListener {
+    fn drop(&mut self) {
+        self.receivers.lock().unwrap().by_fd.remove(&self.fd);
+        lend_to_server(
+            self.cid,
+            api::Opcodes::Close,
+            api::close_offset(self.fd.0, api::close_code::NORMAL),
+            b"",
+        )
+        .ok();
+    }
+}
+
+/// A connection to a websocket server, created by `WebSocketService::open()`, or accepted
+/// by a `WebSocketListener`.
+pub struct WebSocketStream {
+    /// The connection, which changes if the stream reconnects.
+    link: Arc<Mutex<Link>>,
+    cid: xous::CID,
+    /// The other side's half of the opening handshake.
+    response: HttpHead,
+    /// The subprotocol that was chosen during the opening handshake.
+    protocol: Option<String>,
+    /// The task to wake when the poll thread sends something, if the stream is being
+    /// polled asynchronously.
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    waker: Arc<Mutex<Option<Waker>>>,
+    /// Called with everything that arrives, instead of sending it to `receiver`.
+    handler: Arc<Mutex<HandlerSlot>>,
+    /// Packets for this connection are sent here by the poll thread.
+    receiver: mpsc::Receiver<Result<WebSocketPacket, WebSocketError>>,
+    /// The thread that the `Sink` implementations send on, once there's been something
+    /// to send.
+    #[cfg(feature = "async")]
+    sink: Option<async_io::SinkThread>,
+    /// The service's table of receivers, so this stream can remove itself when dropped.
+    receivers: Arc<Mutex<Receivers>>,
+}
+
+impl WebSocketStream {
+    /// Send a text message. Returns the number of bytes that the server accepted.
+    pub fn send_text(&self, text: &str) -> Result<usize, WebSocketError> {
+        self.send(api::MessageKind::Text, text.as_bytes())
+    }
+
+    /// Send a binary message. Returns the number of bytes that the server accepted.
+    pub fn send_binary(&self, data: &[u8]) -> Result<usize, WebSocketError> {
+        self.send(api::MessageKind::Binary, data)
+    }
+
+    /// Wait for the next packet to arrive on this connection. When the connection closes,
+    /// this returns `WebSocketError::Closed` with the code and reason from the remote side,
+    /// and `WebSocketError::ConnectionClosed` after that. If the remote side stopped
+    /// answering keepalive pings, the reason is `api::TIMEOUT_REASON`.
+    ///
+    /// If the stream has a `ReconnectPolicy` and the connection drops, this returns
+    /// `WebSocketError::Reconnecting` before each attempt to reopen it, and then
+    /// `WebSocketError::Reconnected` or `WebSocketError::Closed`. Packets that were in
+    /// flight when it dropped are lost.
+    pub fn recv(&self) -> Result<WebSocketPacket, WebSocketError> {
+        self.receiver
+            .recv()
+            .map_err(|_| WebSocketError::ConnectionClosed)?
+    }
+
+    /// Return the next packet if one has already arrived, or `WebSocketError::WouldBlock`
+    /// if there is nothing waiting.
+    pub fn try_recv(&self) -> Result<WebSocketPacket, WebSocketError> {
+        self.receiver.try_recv().map_err(|e| match e {
+            mpsc::TryRecvError::Empty => WebSocketError::WouldBlock,
+            mpsc::TryRecvError::Disconnected => WebSocketError::ConnectionClosed,
+        })?
+    }
+
+    /// Wait up to `timeout` for the next packet to arrive on this connection.
+    pub fn recv_timeout(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<WebSocketPacket, WebSocketError> {
+        self.receiver.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => WebSocketError::Timeout,
+            mpsc::RecvTimeoutError::Disconnected => WebSocketError::ConnectionClosed,
+        })?
+    }
+
+    /// Start closing the connection with the given close code and reason. The code must
+    /// be one that RFC 6455 allows to be sent, and the reason can be at most 123 bytes.
+    /// The close code and reason that the remote side responds with are returned by
+    /// `recv()` as `WebSocketError::Closed`.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if !api::close_code::is_valid(code) {
+            return Err(WebSocketError::InvalidCloseCode);
+        }
+        if reason.len() > api::MAX_CLOSE_REASON {
+            return Err(WebSocketError::ReasonTooLong);
+        }
+        let fd = {
+            let mut link = self.link.lock().unwrap();
+            link.closed = true;
+            link.fd
+        };
+        lend_to_server(
+            self.cid,
+            api::Opcodes::Close,
+            api::close_offset(fd.0, code),
+            reason.as_bytes(),
+        )
+        .map(|_| ())
+    }
+
+    /// The status line and headers that the server sent in response to the opening
+    /// handshake. For connections accepted by a `WebSocketListener`, this is the request
+    /// line and headers that the client sent instead. This doesn't change if the stream
+    /// reconnects.
+    pub fn response(&self) -> &HttpHead {
+        &self.response
+    }
+
+    /// The subprotocol that was chosen during the opening handshake, or `None` if the
+    /// connection doesn't use one.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Call `handler` with everything that arrives on this stream, rather than queueing it
+    /// to be returned by `recv()`. That includes errors such as `WebSocketError::Closed` and
+    /// reconnect events. Anything that arrived before the handler was set is still
+    /// returned by `recv()`. This replaces any handler that was already set.
+    ///
+    /// The handler runs on the service's poll thread, so nothing arrives on any connection
+    /// while it's running. Reconnect events, and what arrives just after, come from the
+    /// thread that reconnects instead. It may set or clear the handler for its own stream,
+    /// which applies to whatever arrives next. If it panics, it's removed, and later
+    /// packets are returned by `recv()` again.
+    pub fn set_handler<F>(&self, handler: F)
+    where
+        F: FnMut(Result<WebSocketPacket, WebSocketError>) + Send + 'static,
+    {
+        self.handler
+            .lock()
+            .unwrap()
+            .replace(Some(Box::new(handler)));
+    }
+
+    /// Remove the handler, so that packets are returned by `recv()` again.
+    pub fn clear_handler(&self) {
+        self.handler.lock().unwrap().replace(None);
+    }
+
+    /// Ask the server what state the connection is in. A connection that the server no
+    /// longer knows about is reported as `Closed`.
+    pub fn state(&self) -> Result<ConnectionState, WebSocketError> {
+        let msg = xous::Message::new_blocking_scalar(
+            api::Opcodes::State as usize,
+            self.fd().0 as usize,
+            0,
+            0,
+            0,
+        );
+        match xous::send_message(self.cid, msg)? {
+            xous::Result::Scalar1(0) => Ok(ConnectionState::Closed),
+            xous::Result::Scalar1(state) => {
+                ConnectionState::from_usize(state).ok_or(WebSocketError::UnexpectedResponse)
+            }
+            _ => Err(WebSocketError::UnexpectedResponse),
+        }
+    }
+
+    fn send(&self, kind: api::MessageKind, data: &[u8]) -> Result<usize, WebSocketError> {
+        // The server responds with the number of bytes it accepted.
+        lend_to_server(
+            self.cid,
+            api::Opcodes::Send,
+            api::send_offset(self.fd().0, kind),
+            data,
+        )
+    }
+
+    fn fd(&self) -> WebSocketFd {
+        self.link.lock().unwrap().fd
+    }
+}
+
+impl core::fmt::Debug for WebSocketStream {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WebSocketStream")
+            .field("fd", &self.fd().0)
+            .field("cid", &self.cid)
+            .finish()
+    }
+}
+
+/// Iterate over packets as they arrive. Iteration ends when the connection is closed, but
+/// carries on through reconnects.
+impl Iterator for WebSocketStream {
+    type Item = WebSocketPacket;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.recv() {
+                Ok(packet) => return Some(packet),
+                Err(WebSocketError::Reconnecting { .. } | WebSocketError::Reconnected) => (),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Close the connection when the stream goes out of scope.
+impl Drop for WebSocketStream {
+    fn drop(&mut self) {
+        let fd = self.fd();
+        self.receivers.lock().unwrap().by_fd.remove(&fd);
+        // This fails if the connection has already been closed, which is fine. It also
+        // stops any reconnect that's under way.
+        self.close(api::close_code::NORMAL, "").ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options with the given extra headers, and nothing else that adds one.
+    fn with_headers(headers: &[(&str, &str)]) -> OpenOptions {
+        OpenOptions {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn extra_headers() {
+        let options = OpenOptions {
+            cookies: vec![
+                ("session".to_owned(), "abc123".to_owned()),
+                ("theme".to_owned(), "dark".to_owned()),
+            ],
+            protocols: vec!["chat".to_owned(), "superchat".to_owned()],
+            ..with_headers(&[("Authorization", "Bearer token"), ("X-Trace", "")])
+        };
+        assert_eq!(
+            options.request_headers(),
+            Ok(vec![
+                ("Authorization".to_owned(), "Bearer token".to_owned()),
+                ("X-Trace".to_owned(), String::new()),
+                ("Cookie".to_owned(), "session=abc123; theme=dark".to_owned()),
+                (
+                    "Sec-WebSocket-Protocol".to_owned(),
+                    "chat, superchat".to_owned()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn forbidden_headers() {
+        // Hop-by-hop headers, and the ones that the handshake sets itself, in any case.
+        for name in [
+            "Connection",
+            "keep-alive",
+            "Transfer-Encoding",
+            "upgrade",
+            "Host",
+            "Sec-WebSocket-Key",
+            "SEC-WEBSOCKET-EXTENSIONS",
+            "Sec-WebSocket-Protocol",
+        ] {
+            assert_eq!(
+                with_headers(&[(name, "x")]).request_headers(),
+                Err(WebSocketError::ForbiddenHeader),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_headers() {
+        // Line breaks would let a value smuggle in headers of its own.
+        for (name, value) in [
+            ("X-Evil", "a\r\nHost: elsewhere"),
+            ("X-Evil", "a\nb"),
+            ("X-Evil", "a\rb"),
+            ("X Evil", "a"),
+            ("X-Evil:", "a"),
+            ("", "a"),
+        ] {
+            assert_eq!(
+                with_headers(&[(name, value)]).request_headers(),
+                Err(WebSocketError::InvalidHeader),
+                "{:?}: {:?}",
+                name,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_cookies() {
+        for (name, value) in [
+            ("id", "a;b"),
+            ("id", "a,b"),
+            ("id", "a b"),
+            ("id", "\"a\""),
+            ("id", "a\\b"),
+            ("id", "a\r\nb"),
+            ("i d", "a"),
+        ] {
+            let options = OpenOptions {
+                cookies: vec![(name.to_owned(), value.to_owned())],
+                ..Default::default()
+            };
+            assert_eq!(
+                options.request_headers(),
+                Err(WebSocketError::InvalidHeader),
+                "{:?}={:?}",
+                name,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn headers_too_large() {
+        // Each header counts as `Name: value\r\n`, so this is exactly the limit.
+        let value = "x".repeat(api::MAX_REQUEST_HEADERS - "X-Big".len() - 4);
+        let options = with_headers(&[("X-Big", &value)]);
+        assert_eq!(options.request_headers().map(|h| h.len()), Ok(1));
+
+        let value = value + "x";
+        assert_eq!(
+            with_headers(&[("X-Big", &value)]).request_headers(),
+            Err(WebSocketError::HeadersTooLarge)
+        );
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_up_to_max() {
+        let policy = ReconnectPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| policy.delay(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        // Attempts far enough in that the doubling would overflow are still capped.
+        assert_eq!(policy.delay(40), Duration::from_secs(1));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn reconnect_delay_jitter() {
+        let policy = ReconnectPolicy {
+            base_delay: Duration::from_secs(8),
+            max_delay: Duration::from_secs(8),
+            jitter: 0.25,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_secs(6) && delay <= Duration::from_secs(8));
+        }
+
+        // Jitter outside 0.0 to 1.0 is clamped, and NaN or infinity means none at all.
+        for (jitter, min) in [(-1.0, 8), (f32::NAN, 8), (2.0, 0), (f32::INFINITY, 8)] {
+            let policy = ReconnectPolicy {
+                jitter,
+                ..policy.clone()
+            };
+            for _ in 0..100 {
+                let delay = policy.delay(1);
+                assert!(
+                    delay >= Duration::from_secs(min) && delay <= Duration::from_secs(8),
+                    "{}: {:?}",
+                    jitter,
+                    delay
+                );
+            }
+        }
+    }
+}
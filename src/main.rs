@@ -1,6 +1,22 @@
 pub mod api;
-
+mod http;
+mod server;
 
 fn main() {
-    println!("Hello, world!");
+    // The server normally registers under `api::SERVER_NAME`, but tests may run their own
+    // copy under a different name.
+    let name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| api::SERVER_NAME.to_owned());
+
+    let xns = xous_names::XousNames::new().expect("couldn't connect to xous-names");
+    let sid = xns
+        .register_name(&name, Some(api::MAX_CLIENT_CONNECTIONS))
+        .expect("couldn't register websocket server");
+    server::Server::default().run(sid);
+
+    xns.unregister_server(sid).ok();
+    // Safety: this is only reached after `Quit`, after which clients aren't expected to
+    // send anything else.
+    unsafe { xous::destroy_server(sid) }.expect("couldn't destroy websocket server");
 }
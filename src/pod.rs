@@ -0,0 +1,22 @@
+/// Types that can be viewed directly from the bytes of a packet.
+///
+/// # Safety
+///
+/// Implementors must be `Copy`, contain no padding, and be valid for every possible bit
+/// pattern. Integers and floats qualify, while `bool`, `char`, references and most enums
+/// don't.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(
+            // Safety: every bit pattern is a valid value of this type, and it has no padding.
+            unsafe impl Pod for $t {}
+        )*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+// Safety: arrays have no padding between elements, so this holds as long as it holds for `T`.
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
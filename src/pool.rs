@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+/// The size of each page in the pool.
+pub const PAGE_SIZE: usize = 4096;
+
+/// A pool of pages that are lent to the server as `Poll` buffers. Mapping and unmapping
+/// memory is expensive, so pages are returned here when a `WebSocketPacket` is dropped
+/// and reused for the next `Poll`.
+///
+/// The pool keeps between `low_watermark` and `high_watermark` free pages. It starts out
+/// with `low_watermark` pages, and once more than `high_watermark` pages are returned to
+/// it, the extra pages are unmapped until only `low_watermark` remain.
+pub(crate) struct PagePool {
+    free: Mutex<Vec<xous::MemoryRange>>,
+    low_watermark: usize,
+    high_watermark: usize,
+}
+
+impl PagePool {
+    pub fn new(low_watermark: usize, high_watermark: usize) -> PagePool {
+        let high_watermark = high_watermark.max(low_watermark);
+        let pool = PagePool {
+            free: Mutex::new(Vec::with_capacity(high_watermark)),
+            low_watermark,
+            high_watermark,
+        };
+        // Failing to fill the pool up front isn't fatal, since `get()` will try again.
+        let mut free = pool.free.lock().unwrap();
+        while free.len() < low_watermark {
+            match map_page() {
+                Ok(page) => free.push(page),
+                Err(_) => break,
+            }
+        }
+        drop(free);
+        pool
+    }
+
+    /// Take a page from the pool, or map a new one if the pool is empty.
+    pub fn get(&self) -> Result<xous::MemoryRange, xous::Error> {
+        match self.free.lock().unwrap().pop() {
+            Some(page) => Ok(page),
+            None => map_page(),
+        }
+    }
+
+    /// Return a page to the pool. Pages that came from somewhere other than `get()` must
+    /// not be passed here.
+    pub fn put(&self, page: xous::MemoryRange) {
+        let mut free = self.free.lock().unwrap();
+        free.push(page);
+        if free.len() > self.high_watermark {
+            for page in free.drain(self.low_watermark..) {
+                xous::unmap_memory(page).ok();
+            }
+        }
+    }
+}
+
+/// Unmap every free page when the pool goes away. Packets hold a reference to the pool,
+/// so by the time this runs every page has been returned.
+impl Drop for PagePool {
+    fn drop(&mut self) {
+        for page in self.free.get_mut().unwrap().drain(..) {
+            xous::unmap_memory(page).ok();
+        }
+    }
+}
+
+fn map_page() -> Result<xous::MemoryRange, xous::Error> {
+    xous::map_memory(
+        None,
+        None,
+        PAGE_SIZE,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )
+}
@@ -0,0 +1,640 @@
+use super::deflate::{Deflate, DeflateError};
+use super::frame::{self, Decoder, Frame, Opcode, Role};
+use super::handshake::{self, HandshakeError};
+use super::keepalive::{KeepAlive, KeepAliveConfig};
+use super::tls;
+use super::{fail_memory, set_memory_response, ServerState, WebSocketFd, Writer};
+use crate::api;
+use crate::http::{self, HttpHead};
+use api::ConnectionState;
+use std::io::Read;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// The parts of a `ws://` or `wss://` URL that are needed to make a connection.
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Set for `wss://`, which runs over TLS.
+    pub tls: bool,
+    /// The value of the `Host` header, which is the authority as it appeared in the URL.
+    pub host_header: String,
+    /// The path and query, which is what gets requested. This is `/` if the URL has none.
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Option<Url> {
+        let (scheme, rest) = url.split_once("://")?;
+        let (default_port, tls) = match scheme.to_ascii_lowercase().as_str() {
+            "ws" => (80, false),
+            "wss" => (443, true),
+            _ => return None,
+        };
+
+        // Everything up to the first `/` or `?` is the authority, and the fragment is
+        // never sent to the server.
+        let rest = rest.split('#').next().unwrap_or_default();
+        let path_start = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(path_start);
+        let path = match path {
+            "" => "/".to_owned(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_owned(),
+        };
+
+        // IPv6 literals are surrounded by `[]` and contain `:`, so only look for a port
+        // after the closing bracket.
+        let port_start = authority.rfind(']').unwrap_or(0);
+        let (host, port) = match authority[port_start..].rfind(':') {
+            Some(index) => {
+                let index = port_start + index;
+                (&authority[..index], authority[index + 1..].parse().ok()?)
+            }
+            None => (authority, default_port),
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(Url {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            port,
+            tls,
+            host_header: authority.to_owned(),
+            path,
+        })
+    }
+}
+
+/// What a client asked for in an `Open` message.
+pub struct OpenRequest {
+    pub url: Url,
+    /// Offer `permessage-deflate`.
+    pub compression: bool,
+    /// The subprotocols to request, in order of preference.
+    pub protocols: Vec<String>,
+    /// Extra headers to send with the upgrade request, such as `Authorization`.
+    pub headers: Vec<(String, String)>,
+    /// How to keep the connection alive once it's open.
+    pub keepalive: Option<KeepAliveConfig>,
+    /// The TLS configuration, for `wss://` URLs.
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl OpenRequest {
+    /// Parse the contents of an `Open` page, which is laid out like an HTTP head with the
+    /// URL in place of the request line, followed by any root certificates. `offset` is
+    /// the value built by `api::open_offset()`.
+    pub fn parse(page: &[u8], offset: usize) -> Result<OpenRequest, api::Error> {
+        let (flags, _, _) = api::parse_open_offset(offset);
+        let (head, mut certificates) = page.split_at(http::head_len(page).unwrap_or(page.len()));
+        let head = HttpHead::parse(head).ok_or(api::Error::InvalidUrl)?;
+        let url = Url::parse(&head.start_line).ok_or(api::Error::InvalidUrl)?;
+        let size: usize = head
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len() + 4)
+            .sum();
+        if size > api::MAX_REQUEST_HEADERS {
+            return Err(api::Error::HeadersTooLarge);
+        }
+        let mut protocols = Vec::new();
+        let mut headers = Vec::new();
+        for (name, value) in head.headers {
+            if !http::is_token(&name) || !http::is_header_value(&value) {
+                return Err(api::Error::InvalidHeader);
+            }
+            if http::is_reserved_header(&name) {
+                return Err(api::Error::ForbiddenHeader);
+            }
+            if name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
+                protocols.extend(http::split_list(&value).map(|p| p.to_owned()));
+            } else {
+                headers.push((name, value));
+            }
+        }
+        if !protocols.iter().all(|p| http::is_token(p)) {
+            return Err(api::Error::InvalidRequest);
+        }
+
+        let mut roots = Vec::new();
+        while let [a, b, c, d, rest @ ..] = certificates {
+            let len = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+            if len > rest.len() {
+                return Err(api::Error::InvalidRootCertificate);
+            }
+            let (der, rest) = rest.split_at(len);
+            roots.push(rustls::pki_types::CertificateDer::from(der.to_vec()));
+            certificates = rest;
+        }
+        if !certificates.is_empty() {
+            return Err(api::Error::InvalidRootCertificate);
+        }
+        let builtin_roots = flags & api::OPEN_FLAG_NO_BUILTIN_ROOTS == 0;
+        let tls = if url.tls {
+            Some(tls::client_config(&roots, builtin_roots)?)
+        } else {
+            None
+        };
+
+        Ok(OpenRequest {
+            url,
+            compression: flags & api::OPEN_FLAG_NO_COMPRESSION == 0,
+            protocols,
+            headers,
+            keepalive: KeepAliveConfig::from_open_offset(offset),
+            tls,
+        })
+    }
+}
+
+/// A thread that owns a single connection. It connects to the remote host, performs the
+/// opening handshake, responds to the `Open` message in `open` once that's done, and then
+/// reads from the socket until the connection is closed, passing everything it receives to
+/// the owning process.
+pub fn connection_thread(
+    state: Arc<Mutex<ServerState>>,
+    fd: WebSocketFd,
+    request: OpenRequest,
+    mut open: xous::MessageEnvelope,
+) {
+    let url = &request.url;
+    let mut stream = match TcpStream::connect((url.host.as_str(), url.port)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!(
+                "websocket: couldn't connect to {}:{}: {}",
+                url.host, url.port, e
+            );
+            state.lock().unwrap().connections.remove(&fd);
+            fail_memory(&mut open, api::Error::ConnectFailed);
+            return;
+        }
+    };
+
+    // Don't let an unresponsive server hold up the client forever.
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+    let mut session = None;
+    if let Some(config) = &request.tls {
+        match tls::connect(&mut stream, config.clone(), &url.host) {
+            Ok(s) => session = Some(s),
+            Err(e) => {
+                println!(
+                    "websocket: TLS with {}:{} failed: {:?}",
+                    url.host, url.port, e
+                );
+                state.lock().unwrap().connections.remove(&fd);
+                fail_memory(&mut open, e);
+                return;
+            }
+        }
+    }
+    // Over TLS, the upgrade request and response are encrypted like everything else.
+    let result = match &mut session {
+        Some(session) => {
+            handshake::client_handshake(&mut rustls::Stream::new(session, &mut stream), &request)
+        }
+        None => handshake::client_handshake(&mut stream, &request),
+    };
+    let handshake = match result {
+        Ok(result) => result,
+        Err(e) => {
+            println!(
+                "websocket: handshake with {}:{} failed: {:?}",
+                url.host, url.port, e
+            );
+            state.lock().unwrap().connections.remove(&fd);
+            // A response with the wrong status is passed back so the client can see why.
+            let status = match &e {
+                HandshakeError::BadStatus(status, head) => {
+                    write_head(&mut open, head);
+                    *status as usize
+                }
+                _ => 0,
+            };
+            set_memory_response(&mut open, status, e.to_api_error() as usize);
+            return;
+        }
+    };
+    stream.set_read_timeout(None).ok();
+
+    let writer = {
+        let mut state = state.lock().unwrap();
+        // The connection may have been closed while it was still being opened.
+        let Some(connection) = state.connections.get_mut(&fd) else {
+            fail_memory(&mut open, api::Error::ConnectFailed);
+            return;
+        };
+        let deflate = handshake
+            .deflate
+            .map(|config| Deflate::new(config, Role::Client));
+        let writer = match super::make_writer(&stream, Role::Client, session, deflate) {
+            Ok(writer) if connection.transition(ConnectionState::Open).is_ok() => writer,
+            _ => {
+                state.connections.remove(&fd);
+                fail_memory(&mut open, api::Error::ConnectFailed);
+                return;
+            }
+        };
+        connection.writer = Some(writer.clone());
+        connection.keepalive = request.keepalive.map(KeepAlive::new);
+        write_head(&mut open, &handshake.head);
+        set_memory_response(&mut open, fd.as_usize(), 0);
+        writer
+    };
+    // Return the `Open` message, which unblocks the client.
+    drop(open);
+
+    run(state, fd, stream, writer, &handshake.leftover);
+}
+
+/// Read frames from an open connection until it closes, passing everything that arrives to
+/// the owning process. `leftover` holds anything that arrived along with the handshake.
+pub fn run(
+    state: Arc<Mutex<ServerState>>,
+    fd: WebSocketFd,
+    mut stream: TcpStream,
+    writer: Arc<Mutex<Writer>>,
+    leftover: &[u8],
+) {
+    let (role, compressed, encrypted) = {
+        let writer = writer.lock().unwrap();
+        (writer.role, writer.deflate.is_some(), writer.tls.is_some())
+    };
+    // Frames arriving at a client aren't masked, and frames arriving at a server are.
+    let mut decoder = Decoder::new(role);
+    decoder.set_max_payload(MAX_FRAME_PAYLOAD);
+    // Compressed messages are marked with RSV1.
+    if compressed {
+        decoder.allow_rsv(frame::RSV1);
+    }
+    // The other side may have sent frames straight after the handshake. Over TLS, some of
+    // them may still be waiting to be decrypted.
+    decoder.push(leftover);
+    if encrypted && !receive_tls(&writer, fd, &[], &mut decoder) {
+        stream.shutdown(Shutdown::Both).ok();
+    }
+    let mut message = IncomingMessage::default();
+    // The code and reason from the remote side's close frame, once it arrives.
+    let mut remote_close = None;
+    let mut buffer = [0u8; 4096];
+    'read: loop {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) if encrypted => {
+                if !receive_tls(&writer, fd, &buffer[..len], &mut decoder) {
+                    break;
+                }
+            }
+            Ok(len) => decoder.push(&buffer[..len]),
+        }
+        loop {
+            let frame = match decoder.decode() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    super::fail(&state, fd, e.close_code(), "");
+                    break 'read;
+                }
+            };
+            match handle_frame(&state, &writer, fd, &mut message, frame) {
+                Ok(None) => (),
+                Ok(Some(close)) => {
+                    remote_close = Some(close);
+                    break 'read;
+                }
+                Err(code) => {
+                    super::fail(&state, fd, code, "");
+                    break 'read;
+                }
+            }
+        }
+    }
+    stream.shutdown(Shutdown::Both).ok();
+
+    // If the server failed the connection then report why, otherwise report what the
+    // remote side said in its close frame. If neither happened, the connection dropped
+    // out from under us.
+    let mut state = state.lock().unwrap();
+    let failure = state
+        .connections
+        .get_mut(&fd)
+        .and_then(|c| c.failure.take());
+    let (code, reason) = failure
+        .or(remote_close)
+        .unwrap_or((api::close_code::ABNORMAL, String::new()));
+    state.closed(fd, code, &reason);
+}
+
+/// Decrypt data that arrived on a `wss://` connection and pass it to `decoder`. Returns
+/// `false` if the TLS session failed, in which case nothing more can be read.
+fn receive_tls(
+    writer: &Mutex<Writer>,
+    fd: WebSocketFd,
+    data: &[u8],
+    decoder: &mut Decoder,
+) -> bool {
+    let mut writer = writer.lock().unwrap();
+    let Writer { socket, tls, .. } = &mut *writer;
+    let Some(session) = tls else {
+        return false;
+    };
+    match tls::receive(session, socket, data, decoder) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("websocket: TLS error on {:?}: {}", fd, e);
+            false
+        }
+    }
+}
+
+/// How long to wait for the server to answer the opening handshake.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Write the response head into the `Open` buffer, so the client can see the status and
+/// headers. The rest of the buffer is zeroed, which marks where the head ends. A head that
+/// doesn't fit is cut short.
+fn write_head(open: &mut xous::MessageEnvelope, head: &HttpHead) {
+    let Some(mem) = open.body.memory_message_mut() else {
+        return;
+    };
+    let text = head.to_bytes();
+    // Safety: any bit pattern is a valid `u8`.
+    let buffer = unsafe { mem.buf.as_slice_mut::<u8>() };
+    let len = text.len().min(buffer.len());
+    buffer.fill(0);
+    buffer[..len].copy_from_slice(&text[..len]);
+}
+
+/// The most that a single compressed frame may inflate to.
+const MAX_INFLATED_PAYLOAD: usize = 64 * 1024 * 1024;
+
+/// The largest frame that will be accepted. Messages may be larger than this, since they
+/// can be split across several frames, but each frame is buffered in full before it's
+/// passed on.
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024 * 1024;
+
+/// The data message that is currently arriving, which may be split across several frames.
+#[derive(Default)]
+struct IncomingMessage {
+    kind: Option<api::MessageKind>,
+    /// Set if the message was compressed with `permessage-deflate`.
+    compressed: bool,
+    /// The end of the previous text frame, if it stopped partway through a UTF-8 character.
+    partial_utf8: Vec<u8>,
+}
+
+impl IncomingMessage {
+    /// Check that the next piece of a text message is valid UTF-8, allowing characters to
+    /// be split between frames as long as they are complete by the final one.
+    fn check_utf8(&mut self, data: &[u8], fin: bool) -> bool {
+        let mut bytes = std::mem::take(&mut self.partial_utf8);
+        bytes.extend_from_slice(data);
+        match std::str::from_utf8(&bytes) {
+            Ok(_) => true,
+            // `error_len()` is `None` when the only problem is that the data stops partway
+            // through a character.
+            Err(e) if e.error_len().is_none() && !fin => {
+                self.partial_utf8 = bytes[e.valid_up_to()..].to_vec();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Act on a frame from the remote side. Data is passed on to the owner, pings are answered,
+/// and a close frame is answered and returned so the connection can finish closing. An
+/// error holds the code that the connection should be failed with.
+///
+/// `state` isn't kept locked while replies are written or messages are inflated, since
+/// `writer` is locked for those.
+fn handle_frame(
+    state: &Mutex<ServerState>,
+    writer: &Mutex<Writer>,
+    fd: WebSocketFd,
+    message: &mut IncomingMessage,
+    frame: Frame,
+) -> Result<Option<(u16, String)>, u16> {
+    let close = match frame.opcode {
+        Opcode::Close => Some(frame::parse_close_payload(&frame.payload)?),
+        _ => None,
+    };
+    let reply = {
+        let mut state = state.lock().unwrap();
+        let Some(connection) = state.connections.get_mut(&fd) else {
+            return Ok(None);
+        };
+        // Anything arriving shows that the other side is still there. Pongs for keepalive
+        // pings are used up here, since the owner didn't ask for them.
+        let pong = (frame.opcode == Opcode::Pong).then_some(frame.payload.as_slice());
+        if let Some(keepalive) = &mut connection.keepalive {
+            if keepalive.received(pong) {
+                return Ok(None);
+            }
+        }
+        match (&close, frame.opcode) {
+            // If this side didn't start the close, echo the code back to complete the
+            // handshake.
+            (Some((code, _)), _) if connection.state == ConnectionState::Open => {
+                connection.transition(ConnectionState::Closing).ok();
+                Some(Frame::close(*code, ""))
+            }
+            (None, Opcode::Ping) if connection.state == ConnectionState::Open => {
+                Some(Frame::new(Opcode::Pong, frame.payload.clone()))
+            }
+            _ => None,
+        }
+    };
+    if let Some(reply) = reply {
+        writer.lock().unwrap().write_frame(&reply).ok();
+    }
+    let kind = match frame.opcode {
+        Opcode::Close => return Ok(close),
+        Opcode::Ping => api::MessageKind::Ping,
+        Opcode::Pong => api::MessageKind::Pong,
+        Opcode::Text => api::MessageKind::Text,
+        Opcode::Binary => api::MessageKind::Binary,
+        // The decoder only lets continuations through in the middle of a message.
+        Opcode::Continuation => message.kind.unwrap_or(api::MessageKind::Binary),
+    };
+
+    // RSV1 marks a compressed message, so it may only be set on the first frame of a data
+    // message. The decoder has already refused it if compression wasn't negotiated.
+    let rsv1 = frame.rsv & frame::RSV1 != 0;
+    if rsv1 && (frame.opcode.is_control() || frame.opcode == Opcode::Continuation) {
+        return Err(api::close_code::PROTOCOL_ERROR);
+    }
+
+    // Pieces of a fragmented message are passed on as they arrive, and the owner puts
+    // them back together.
+    let mut flags = 0;
+    let mut payload = frame.payload;
+    if !frame.opcode.is_control() {
+        if frame.opcode != Opcode::Continuation {
+            message.compressed = rsv1;
+        }
+        if message.compressed {
+            let mut writer = writer.lock().unwrap();
+            let Some(deflate) = writer.deflate.as_mut() else {
+                return Err(api::close_code::PROTOCOL_ERROR);
+            };
+            payload = deflate
+                .decompress(&payload, frame.fin, MAX_INFLATED_PAYLOAD)
+                .map_err(|e| match e {
+                    DeflateError::Corrupt => api::close_code::INVALID_PAYLOAD,
+                    DeflateError::TooLarge => api::close_code::MESSAGE_TOO_BIG,
+                })?;
+        }
+        if kind == api::MessageKind::Text && !message.check_utf8(&payload, frame.fin) {
+            return Err(api::close_code::INVALID_PAYLOAD);
+        }
+        message.kind = if frame.fin { None } else { Some(kind) };
+        if !frame.fin {
+            flags = api::POLL_FLAG_MORE;
+        }
+    }
+    // An owner that has stopped polling mustn't be able to make the server buffer
+    // everything that arrives for it.
+    let mut state = state.lock().unwrap();
+    if state.backlogged(fd) {
+        return Err(api::close_code::POLICY_VIOLATION);
+    }
+    state.deliver(fd, kind, flags, payload);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The host, port and path that `url` parses to.
+    fn parse(url: &str) -> Option<(String, u16, String)> {
+        Url::parse(url).map(|url| (url.host, url.port, url.path))
+    }
+
+    #[test]
+    fn url_ports() {
+        let url = Url::parse("wss://example.com").unwrap();
+        assert!(url.tls);
+        assert_eq!((url.port, url.host_header.as_str()), (443, "example.com"));
+        let url = Url::parse("WS://example.com:8080/").unwrap();
+        assert!(!url.tls);
+        assert_eq!(
+            (url.port, url.host_header.as_str()),
+            (8080, "example.com:8080")
+        );
+        assert!(Url::parse("ws://example.com:http/").is_none());
+        assert!(Url::parse("ws://example.com:65536/").is_none());
+        assert!(Url::parse("http://example.com/").is_none());
+    }
+
+    #[test]
+    fn url_ipv6() {
+        let url = Url::parse("ws://[::1]:9000/chat").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("::1", 9000, "/chat")
+        );
+        // The brackets stay in the `Host` header.
+        assert_eq!(url.host_header, "[::1]:9000");
+        assert_eq!(
+            parse("ws://[fe80::1:2]"),
+            Some(("fe80::1:2".to_owned(), 80, "/".to_owned()))
+        );
+    }
+
+    #[test]
+    fn url_paths() {
+        assert_eq!(
+            parse("ws://example.com/a/b?c=d"),
+            Some(("example.com".to_owned(), 80, "/a/b?c=d".to_owned()))
+        );
+        // A query with no path still needs the `/` in the request line.
+        assert_eq!(
+            parse("ws://example.com?c=d"),
+            Some(("example.com".to_owned(), 80, "/?c=d".to_owned()))
+        );
+        assert_eq!(
+            parse("ws://example.com:81?c=d"),
+            Some(("example.com".to_owned(), 81, "/?c=d".to_owned()))
+        );
+    }
+
+    #[test]
+    fn url_fragment() {
+        // The fragment is never sent.
+        assert_eq!(
+            parse("ws://example.com/chat#top"),
+            Some(("example.com".to_owned(), 80, "/chat".to_owned()))
+        );
+        assert_eq!(
+            parse("ws://example.com#top"),
+            Some(("example.com".to_owned(), 80, "/".to_owned()))
+        );
+        assert_eq!(
+            parse("ws://example.com/?a=b#c?d"),
+            Some(("example.com".to_owned(), 80, "/?a=b".to_owned()))
+        );
+    }
+
+    #[test]
+    fn url_missing_host() {
+        for url in [
+            "ws://",
+            "ws:///chat",
+            "ws://:80/",
+            "ws://?a=b",
+            "ws://#top",
+            "example.com",
+        ] {
+            assert!(Url::parse(url).is_none(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn utf8_split_across_frames() {
+        // U+1F600 is four bytes long, and arrives a piece at a time.
+        let mut message = IncomingMessage::default();
+        let smiley = "\u{1f600}".as_bytes();
+        assert!(message.check_utf8(b"hi \xf0", false));
+        assert!(message.check_utf8(&smiley[1..2], false));
+        assert!(message.check_utf8(&smiley[2..], false));
+        assert!(message.partial_utf8.is_empty());
+        assert!(message.check_utf8("é".as_bytes(), true));
+
+        let e = "é".as_bytes();
+        assert!(message.check_utf8(&e[..1], false));
+        assert!(message.check_utf8(&e[1..], true));
+    }
+
+    #[test]
+    fn utf8_incomplete_at_end() {
+        // The final frame can't leave a character unfinished.
+        let mut message = IncomingMessage::default();
+        assert!(message.check_utf8(&[0xe2, 0x82], false));
+        assert!(!message.check_utf8(&[], true));
+
+        let mut message = IncomingMessage::default();
+        assert!(!message.check_utf8(&[b'a', 0xe2, 0x82], true));
+    }
+
+    #[test]
+    fn utf8_invalid() {
+        let mut message = IncomingMessage::default();
+        assert!(!message.check_utf8(&[0xff], false));
+
+        // A character that was started has to be continued.
+        let mut message = IncomingMessage::default();
+        assert!(message.check_utf8(&[0xc3], false));
+        assert!(!message.check_utf8(b"A", true));
+
+        // Surrogates aren't allowed, which shows by the second byte.
+        let mut message = IncomingMessage::default();
+        assert!(!message.check_utf8(&[0xed, 0xa0], false));
+    }
+}
@@ -0,0 +1,330 @@
+//! The `permessage-deflate` extension from RFC 7692, which compresses each message with
+//! raw DEFLATE.
+
+use super::frame::Role;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// The name of the extension in `Sec-WebSocket-Extensions`.
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// Every compressed message ends with an empty stored block, which is left off on the wire
+/// and has to be put back before inflating.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The negotiated parameters of the extension.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeflateConfig {
+    /// The server resets its compressor after every message.
+    pub server_no_context_takeover: bool,
+    /// The client resets its compressor after every message.
+    pub client_no_context_takeover: bool,
+    /// The largest window the server may compress with, as a power of two.
+    pub server_max_window_bits: u8,
+    /// The largest window the client may compress with, as a power of two.
+    pub client_max_window_bits: u8,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+        }
+    }
+}
+
+impl DeflateConfig {
+    /// The offer that a client sends. It lets the server pick the client's window size.
+    pub fn offer() -> String {
+        format!("{}; client_max_window_bits", EXTENSION_NAME)
+    }
+
+    /// Parse one element of a `Sec-WebSocket-Extensions` header, such as
+    /// `permessage-deflate; client_max_window_bits=10`. Returns `None` if it's a different
+    /// extension, or if any parameter is unknown, repeated or out of range.
+    ///
+    /// `client_max_window_bits` may appear without a value in an offer, which is returned
+    /// as 15.
+    pub fn parse(element: &str) -> Option<DeflateConfig> {
+        let mut params = element.split(';').map(|p| p.trim());
+        if !params.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+            return None;
+        }
+
+        let mut config = DeflateConfig::default();
+        let mut seen = Vec::new();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            let name = name.to_ascii_lowercase();
+            if seen.contains(&name) {
+                return None;
+            }
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => config.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => config.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) => {
+                    config.server_max_window_bits = parse_window_bits(bits)?
+                }
+                ("client_max_window_bits", None) => (),
+                ("client_max_window_bits", Some(bits)) => {
+                    config.client_max_window_bits = parse_window_bits(bits)?
+                }
+                _ => return None,
+            }
+            seen.push(name);
+        }
+        Some(config)
+    }
+
+    /// Format the parameters that differ from the defaults, as a server's response to an
+    /// offer.
+    pub fn to_header(self) -> String {
+        let mut header = EXTENSION_NAME.to_owned();
+        if self.server_no_context_takeover {
+            header += "; server_no_context_takeover";
+        }
+        if self.client_no_context_takeover {
+            header += "; client_no_context_takeover";
+        }
+        if self.server_max_window_bits != 15 {
+            header += &format!("; server_max_window_bits={}", self.server_max_window_bits);
+        }
+        if self.client_max_window_bits != 15 {
+            header += &format!("; client_max_window_bits={}", self.client_max_window_bits);
+        }
+        header
+    }
+
+    /// The window size that `role` compresses with, and whether it resets after each
+    /// message.
+    fn sending(&self, role: Role) -> (u8, bool) {
+        match role {
+            Role::Client => (self.client_max_window_bits, self.client_no_context_takeover),
+            Role::Server => (self.server_max_window_bits, self.server_no_context_takeover),
+        }
+    }
+
+    /// Returns `true` if `role` can compress within the negotiated window. A window of 256
+    /// bytes is valid in the extension, but zlib can't produce raw DEFLATE with one.
+    pub fn can_send(&self, role: Role) -> bool {
+        self.sending(role).0 >= 9
+    }
+}
+
+/// Window sizes are powers of two from 2^8 to 2^15.
+fn parse_window_bits(value: &str) -> Option<u8> {
+    match value.parse() {
+        Ok(bits @ 8..=15) if !value.starts_with('0') => Some(bits),
+        _ => None,
+    }
+}
+
+/// Errors that fail a compressed connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeflateError {
+    /// The compressed data was corrupt.
+    Corrupt,
+    /// A message inflated to more than the limit.
+    TooLarge,
+}
+
+/// The compression state for one connection.
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// Reset the compressor after each message.
+    compress_reset: bool,
+    /// Reset the decompressor after each message, since the other side resets its
+    /// compressor.
+    decompress_reset: bool,
+}
+
+impl Deflate {
+    /// Set up compression for this end of the connection, which is `role`. `config` must
+    /// satisfy `can_send(role)`.
+    pub fn new(config: DeflateConfig, role: Role) -> Deflate {
+        let (window_bits, compress_reset) = config.sending(role);
+        // The other side may compress with a smaller window than this, but inflating
+        // with the largest window handles every size.
+        let decompress_reset = match role {
+            Role::Client => config.server_no_context_takeover,
+            Role::Server => config.client_no_context_takeover,
+        };
+        Deflate {
+            compress: Compress::new_with_window_bits(Compression::default(), false, window_bits),
+            decompress: Decompress::new_with_window_bits(false, 15),
+            compress_reset,
+            decompress_reset,
+        }
+    }
+
+    /// Compress a whole message.
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            out.reserve(out.len().max(64));
+            // Compressing into memory can't fail.
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .ok();
+            // The sync flush is complete once there's room left over in the output.
+            if (self.compress.total_in() - start) as usize == data.len()
+                && out.len() < out.capacity()
+            {
+                break;
+            }
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        if self.compress_reset {
+            self.compress.reset();
+        }
+        out
+    }
+
+    /// Inflate the next frame of a compressed message. `fin` is set on the last frame.
+    /// The output may be at most `max_len` bytes.
+    pub fn decompress(
+        &mut self,
+        data: &[u8],
+        fin: bool,
+        max_len: usize,
+    ) -> Result<Vec<u8>, DeflateError> {
+        let mut out = Vec::with_capacity(data.len() * 2 + 64);
+        self.inflate(data, &mut out, max_len)?;
+        if fin {
+            self.inflate(&TAIL, &mut out, max_len)?;
+            if self.decompress_reset {
+                self.decompress.reset(false);
+            }
+        }
+        Ok(out)
+    }
+
+    fn inflate(
+        &mut self,
+        data: &[u8],
+        out: &mut Vec<u8>,
+        max_len: usize,
+    ) -> Result<(), DeflateError> {
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.len().max(64));
+            }
+            let status = self
+                .decompress
+                .decompress_vec(&data[consumed..], out, FlushDecompress::Sync)
+                .map_err(|_| DeflateError::Corrupt)?;
+            if out.len() > max_len {
+                return Err(DeflateError::TooLarge);
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            // Finished once all the input is used and there was room to spare, which means
+            // nothing more is buffered inside the decompressor.
+            let done = consumed == data.len() && out.len() < out.capacity();
+            if done || status == Status::StreamEnd {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_parameters() {
+        assert_eq!(
+            DeflateConfig::parse("permessage-deflate"),
+            Some(DeflateConfig::default())
+        );
+        // A client offer may leave the value out, letting the server choose.
+        assert_eq!(
+            DeflateConfig::parse(&DeflateConfig::offer()),
+            Some(DeflateConfig::default())
+        );
+        assert_eq!(
+            DeflateConfig::parse(
+                "Permessage-Deflate; server_no_context_takeover; CLIENT_NO_CONTEXT_TAKEOVER; \
+                 server_max_window_bits=10; client_max_window_bits=\"9\""
+            ),
+            Some(DeflateConfig {
+                server_no_context_takeover: true,
+                client_no_context_takeover: true,
+                server_max_window_bits: 10,
+                client_max_window_bits: 9,
+            })
+        );
+    }
+
+    #[test]
+    fn reject_invalid_parameters() {
+        for element in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate; unknown",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; server_no_context_takeover=1",
+            // The server's window always needs a value.
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=7",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; client_max_window_bits=010",
+            "permessage-deflate; client_max_window_bits=abc",
+        ] {
+            assert_eq!(DeflateConfig::parse(element), None, "{}", element);
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let config = DeflateConfig {
+            server_no_context_takeover: true,
+            client_max_window_bits: 12,
+            ..DeflateConfig::default()
+        };
+        let header = config.to_header();
+        assert_eq!(
+            header,
+            "permessage-deflate; server_no_context_takeover; client_max_window_bits=12"
+        );
+        assert_eq!(DeflateConfig::parse(&header), Some(config));
+        assert_eq!(DeflateConfig::default().to_header(), "permessage-deflate");
+    }
+
+    #[test]
+    fn rfc_example() {
+        // RFC 7692 section 7.2.3.1: "Hello" compressed, without the trailing empty block.
+        let hello = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        let mut client = Deflate::new(DeflateConfig::default(), Role::Client);
+        assert_eq!(client.decompress(&hello, true, 1024).unwrap(), b"Hello");
+        // The window carries over, so a repeated message can refer back to the first one.
+        let mut server = Deflate::new(DeflateConfig::default(), Role::Server);
+        let first = server.compress(b"Hello");
+        let second = server.compress(b"Hello");
+        assert!(second.len() < first.len());
+        assert_eq!(client.decompress(&first, true, 1024).unwrap(), b"Hello");
+        assert_eq!(client.decompress(&second, true, 1024).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn decompress_limit() {
+        let mut server = Deflate::new(DeflateConfig::default(), Role::Server);
+        let compressed = server.compress(&[0; 4096]);
+        let mut client = Deflate::new(DeflateConfig::default(), Role::Client);
+        assert_eq!(
+            client.decompress(&compressed, true, 100),
+            Err(DeflateError::TooLarge)
+        );
+    }
+}
@@ -0,0 +1,525 @@
+//! Websocket frames, as described in RFC 6455 section 5.
+//!
+//! `Frame::encode()` turns a frame into bytes that can be written to a socket, and
+//! `Decoder` turns bytes read from a socket back into frames. The decoder doesn't need the
+//! whole frame at once, so it can be fed whatever each read happens to return.
+
+use crate::api::close_code;
+
+/// The frame opcodes defined by RFC 6455. Opcodes 3-7 and 11-15 are reserved.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation = 0,
+    Text = 1,
+    Binary = 2,
+    Close = 8,
+    Ping = 9,
+    Pong = 10,
+}
+
+impl Opcode {
+    pub fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0 => Some(Opcode::Continuation),
+            1 => Some(Opcode::Text),
+            2 => Some(Opcode::Binary),
+            8 => Some(Opcode::Close),
+            9 => Some(Opcode::Ping),
+            10 => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    /// Close, ping and pong are control frames, which may not be fragmented and may carry
+    /// at most `MAX_CONTROL_PAYLOAD` bytes.
+    pub fn is_control(&self) -> bool {
+        *self as u8 & 0x8 != 0
+    }
+}
+
+/// The most payload that a control frame may carry.
+pub const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// The reserved bits in the first byte of a frame. They have no meaning unless an extension
+/// gives them one.
+pub const RSV1: u8 = 0x40;
+pub const RSV2: u8 = 0x20;
+pub const RSV3: u8 = 0x10;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+/// Which end of the connection this is. Clients mask every frame that they send, and
+/// servers never do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// A single websocket frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Set on the last frame of a message.
+    pub fin: bool,
+    /// Any of `RSV1`, `RSV2` and `RSV3`.
+    pub rsv: u8,
+    pub opcode: Opcode,
+    /// The payload, after unmasking.
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// An unfragmented frame with no reserved bits set.
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            rsv: 0,
+            opcode,
+            payload,
+        }
+    }
+
+    /// A close frame with the given code and reason. `NO_STATUS` produces an empty close
+    /// frame, since that code may not be sent.
+    pub fn close(code: u16, reason: &str) -> Frame {
+        let mut payload = Vec::new();
+        if code != close_code::NO_STATUS {
+            payload.extend_from_slice(&code.to_be_bytes());
+            payload.extend_from_slice(reason.as_bytes());
+        }
+        Frame::new(Opcode::Close, payload)
+    }
+
+    /// Serialize the frame. `mask` must be given when sending as a client, and must be
+    /// unpredictable.
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut out = Vec::with_capacity(len + 14);
+        out.push(
+            if self.fin { FIN } else { 0 } | self.rsv & (RSV1 | RSV2 | RSV3) | self.opcode as u8,
+        );
+
+        // Lengths up to 125 fit in the second byte, and longer ones follow it in either
+        // 16 or 64 bits.
+        let mask_bit = if mask.is_some() { MASKED } else { 0 };
+        if len <= 125 {
+            out.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        match mask {
+            Some(mask) => {
+                out.extend_from_slice(&mask);
+                let start = out.len();
+                out.extend_from_slice(&self.payload);
+                apply_mask(&mut out[start..], mask);
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+}
+
+/// XOR `data` with `mask`. Masking and unmasking are the same operation.
+pub fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Reasons that a frame can't be decoded. Each of these fails the connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The opcode is one of the reserved ones.
+    UnknownOpcode(u8),
+    /// A reserved bit was set that no negotiated extension uses.
+    ReservedBits,
+    /// A control frame was fragmented, or had more than 125 bytes of payload.
+    InvalidControlFrame,
+    /// A continuation frame arrived outside of a fragmented message, or a new message
+    /// started before the previous one was finished.
+    InvalidFragment,
+    /// The frame was masked when it shouldn't be, or wasn't masked when it should be.
+    InvalidMask,
+    /// The most significant bit of a 64-bit length was set.
+    InvalidLength,
+    /// The payload is larger than the decoder was told to accept.
+    TooLarge,
+}
+
+impl FrameError {
+    /// The close code to fail the connection with.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            FrameError::TooLarge => close_code::MESSAGE_TOO_BIG,
+            _ => close_code::PROTOCOL_ERROR,
+        }
+    }
+}
+
+/// Decodes frames from a stream of bytes.
+pub struct Decoder {
+    buffer: Vec<u8>,
+    role: Role,
+    /// Reserved bits that a negotiated extension has given a meaning to.
+    allowed_rsv: u8,
+    max_payload: usize,
+    /// Set while a fragmented message is arriving, so that continuation frames can be
+    /// checked.
+    in_message: bool,
+}
+
+impl Decoder {
+    /// Create a decoder for frames arriving at `role`. A client expects unmasked frames,
+    /// and a server expects masked ones.
+    pub fn new(role: Role) -> Decoder {
+        Decoder {
+            buffer: Vec::new(),
+            role,
+            allowed_rsv: 0,
+            max_payload: usize::MAX,
+            in_message: false,
+        }
+    }
+
+    /// Allow frames to have the given reserved bits set, once an extension that uses them
+    /// has been negotiated.
+    pub fn allow_rsv(&mut self, rsv: u8) {
+        self.allowed_rsv |= rsv;
+    }
+
+    /// Refuse frames whose payload is larger than `max_payload`, rather than buffering them.
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
+
+    /// Add bytes that have been read from the socket.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Return the next complete frame, or `None` if more bytes are needed first. Once this
+    /// returns an error the connection should be failed, since the stream can't be
+    /// resynchronized.
+    pub fn decode(&mut self) -> Result<Option<Frame>, FrameError> {
+        let [first, second, ..] = self.buffer[..] else {
+            return Ok(None);
+        };
+
+        let fin = first & FIN != 0;
+        let rsv = first & (RSV1 | RSV2 | RSV3);
+        let opcode = Opcode::from_u8(first & 0xf).ok_or(FrameError::UnknownOpcode(first & 0xf))?;
+        if rsv & !self.allowed_rsv != 0 {
+            return Err(FrameError::ReservedBits);
+        }
+        let masked = second & MASKED != 0;
+        if masked != (self.role == Role::Server) {
+            return Err(FrameError::InvalidMask);
+        }
+
+        // Work out how long the header is, and stop if it hasn't all arrived.
+        let (len, mut header_len) = match second & 0x7f {
+            126 => {
+                let Some(bytes) = self.buffer.get(2..4) else {
+                    return Ok(None);
+                };
+                (u16::from_be_bytes(bytes.try_into().unwrap()) as u64, 4)
+            }
+            127 => {
+                let Some(bytes) = self.buffer.get(2..10) else {
+                    return Ok(None);
+                };
+                let len = u64::from_be_bytes(bytes.try_into().unwrap());
+                if len & (1 << 63) != 0 {
+                    return Err(FrameError::InvalidLength);
+                }
+                (len, 10)
+            }
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(FrameError::InvalidControlFrame);
+        }
+        let len = usize::try_from(len).map_err(|_| FrameError::TooLarge)?;
+        if len > self.max_payload {
+            return Err(FrameError::TooLarge);
+        }
+        // Data frames have to follow on from whatever message is in progress.
+        if !opcode.is_control() {
+            let continuation = opcode == Opcode::Continuation;
+            if continuation != self.in_message {
+                return Err(FrameError::InvalidFragment);
+            }
+        }
+
+        let mask = if masked {
+            let Some(bytes) = self.buffer.get(header_len..header_len + 4) else {
+                return Ok(None);
+            };
+            header_len += 4;
+            Some(<[u8; 4]>::try_from(bytes).unwrap())
+        } else {
+            None
+        };
+        if self.buffer.len() - header_len < len {
+            return Ok(None);
+        }
+
+        let mut payload: Vec<u8> = self
+            .buffer
+            .drain(..header_len + len)
+            .skip(header_len)
+            .collect();
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        if !opcode.is_control() {
+            self.in_message = !fin;
+        }
+        Ok(Some(Frame {
+            fin,
+            rsv,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+/// Decode the payload of a close frame into a code and reason. An empty payload means no
+/// code was given. Anything else that can't be sent in a close frame is an error, and the
+/// code to fail the connection with is returned.
+pub fn parse_close_payload(payload: &[u8]) -> Result<(u16, String), u16> {
+    match payload {
+        [] => Ok((close_code::NO_STATUS, String::new())),
+        [hi, lo, reason @ ..] => {
+            let code = u16::from_be_bytes([*hi, *lo]);
+            if !close_code::is_valid(code) {
+                return Err(close_code::PROTOCOL_ERROR);
+            }
+            let reason = std::str::from_utf8(reason).map_err(|_| close_code::INVALID_PAYLOAD)?;
+            Ok((code, reason.to_owned()))
+        }
+        _ => Err(close_code::PROTOCOL_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// Decode the first frame in `bytes`.
+    fn decode_one(role: Role, bytes: &[u8]) -> Result<Option<Frame>, FrameError> {
+        let mut decoder = Decoder::new(role);
+        decoder.push(bytes);
+        decoder.decode()
+    }
+
+    #[test]
+    fn rfc_examples() {
+        // RFC 6455 section 5.7: a single-frame unmasked and masked text message.
+        let unmasked = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let masked = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let hello = Frame::new(Opcode::Text, b"Hello".to_vec());
+        assert_eq!(hello.encode(None), unmasked);
+        assert_eq!(hello.encode(Some(MASK)), masked);
+        assert_eq!(decode_one(Role::Client, &unmasked), Ok(Some(hello.clone())));
+        assert_eq!(decode_one(Role::Server, &masked), Ok(Some(hello)));
+    }
+
+    #[test]
+    fn length_encodings() {
+        for (len, header) in [(125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            let frame = Frame::new(Opcode::Binary, vec![0xab; len]);
+            let bytes = frame.encode(None);
+            assert_eq!(bytes.len(), header + len);
+            match header {
+                2 => assert_eq!(bytes[1], len as u8),
+                4 => assert_eq!(bytes[1], 126),
+                _ => assert_eq!(bytes[1], 127),
+            }
+            assert_eq!(decode_one(Role::Client, &bytes), Ok(Some(frame)));
+        }
+    }
+
+    #[test]
+    fn masking_in_both_roles() {
+        let frame = Frame::new(Opcode::Binary, (0..=255).collect());
+        let masked = frame.encode(Some(MASK));
+        let unmasked = frame.encode(None);
+        assert_ne!(masked[6..], unmasked[4..]);
+
+        // A server expects masked frames, and a client expects unmasked ones.
+        assert_eq!(decode_one(Role::Server, &masked), Ok(Some(frame.clone())));
+        assert_eq!(decode_one(Role::Client, &unmasked), Ok(Some(frame)));
+        assert_eq!(
+            decode_one(Role::Server, &unmasked),
+            Err(FrameError::InvalidMask)
+        );
+        assert_eq!(
+            decode_one(Role::Client, &masked),
+            Err(FrameError::InvalidMask)
+        );
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let frames = [
+            Frame::new(Opcode::Text, b"first".to_vec()),
+            Frame::new(Opcode::Binary, vec![7; 300]),
+            Frame::new(Opcode::Ping, Vec::new()),
+        ];
+        let bytes: Vec<u8> = frames.iter().flat_map(|f| f.encode(Some(MASK))).collect();
+        let mut decoder = Decoder::new(Role::Server);
+        let mut decoded = Vec::new();
+        for byte in bytes {
+            decoder.push(&[byte]);
+            while let Some(frame) = decoder.decode().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn invalid_control_frames() {
+        let long_ping = Frame::new(Opcode::Ping, vec![0; 126]);
+        assert_eq!(
+            decode_one(Role::Client, &long_ping.encode(None)),
+            Err(FrameError::InvalidControlFrame)
+        );
+        let fragmented_close = Frame {
+            fin: false,
+            ..Frame::close(close_code::NORMAL, "")
+        };
+        assert_eq!(
+            decode_one(Role::Client, &fragmented_close.encode(None)),
+            Err(FrameError::InvalidControlFrame)
+        );
+        let max_pong = Frame::new(Opcode::Pong, vec![0; MAX_CONTROL_PAYLOAD]);
+        assert_eq!(
+            decode_one(Role::Client, &max_pong.encode(None)),
+            Ok(Some(max_pong))
+        );
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let frame = Frame {
+            rsv: RSV1,
+            ..Frame::new(Opcode::Text, b"x".to_vec())
+        };
+        let bytes = frame.encode(None);
+        assert_eq!(
+            decode_one(Role::Client, &bytes),
+            Err(FrameError::ReservedBits)
+        );
+
+        let mut decoder = Decoder::new(Role::Client);
+        decoder.allow_rsv(RSV1);
+        decoder.push(&bytes);
+        assert_eq!(decoder.decode(), Ok(Some(frame)));
+
+        // Allowing one bit doesn't allow the others.
+        let rsv2 = Frame {
+            rsv: RSV2,
+            ..Frame::new(Opcode::Text, b"x".to_vec())
+        };
+        decoder.push(&rsv2.encode(None));
+        assert_eq!(decoder.decode(), Err(FrameError::ReservedBits));
+    }
+
+    #[test]
+    fn unknown_opcode_and_length() {
+        assert_eq!(
+            decode_one(Role::Client, &[0x83, 0x00]),
+            Err(FrameError::UnknownOpcode(3))
+        );
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        assert_eq!(
+            decode_one(Role::Client, &bytes),
+            Err(FrameError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn max_payload() {
+        let mut decoder = Decoder::new(Role::Client);
+        decoder.set_max_payload(10);
+        // Only the header is needed to refuse the frame.
+        decoder.push(&Frame::new(Opcode::Binary, vec![0; 11]).encode(None)[..2]);
+        assert_eq!(decoder.decode(), Err(FrameError::TooLarge));
+    }
+
+    #[test]
+    fn continuation_ordering() {
+        let start = Frame {
+            fin: false,
+            ..Frame::new(Opcode::Text, b"a".to_vec())
+        };
+        let middle = Frame {
+            fin: false,
+            ..Frame::new(Opcode::Continuation, b"b".to_vec())
+        };
+        let end = Frame::new(Opcode::Continuation, b"c".to_vec());
+        let ping = Frame::new(Opcode::Ping, b"p".to_vec());
+
+        // Control frames may arrive in the middle of a fragmented message.
+        let mut decoder = Decoder::new(Role::Client);
+        for frame in [&start, &middle, &ping, &end] {
+            decoder.push(&frame.encode(None));
+            assert_eq!(decoder.decode(), Ok(Some(frame.clone())));
+        }
+
+        // A continuation can't start a message.
+        assert_eq!(
+            decode_one(Role::Client, &end.encode(None)),
+            Err(FrameError::InvalidFragment)
+        );
+
+        // A new message can't start before the previous one has finished.
+        let mut decoder = Decoder::new(Role::Client);
+        decoder.push(&start.encode(None));
+        decoder.push(&Frame::new(Opcode::Binary, Vec::new()).encode(None));
+        assert_eq!(decoder.decode(), Ok(Some(start)));
+        assert_eq!(decoder.decode(), Err(FrameError::InvalidFragment));
+    }
+
+    #[test]
+    fn close_payloads() {
+        assert_eq!(
+            parse_close_payload(&[]),
+            Ok((close_code::NO_STATUS, String::new()))
+        );
+        assert_eq!(
+            parse_close_payload(&Frame::close(close_code::GOING_AWAY, "bye").payload),
+            Ok((close_code::GOING_AWAY, "bye".to_owned()))
+        );
+        assert!(Frame::close(close_code::NO_STATUS, "ignored")
+            .payload
+            .is_empty());
+        assert_eq!(
+            parse_close_payload(&[0x03]),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+        assert_eq!(
+            parse_close_payload(&close_code::ABNORMAL.to_be_bytes()),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+        assert_eq!(
+            parse_close_payload(&[0x03, 0xe8, 0xff]),
+            Err(close_code::INVALID_PAYLOAD)
+        );
+    }
+}
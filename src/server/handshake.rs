@@ -0,0 +1,297 @@
+//! The opening handshake from RFC 6455 section 4, which upgrades an HTTP/1.1 connection
+//! to a websocket.
+
+use super::connection::OpenRequest;
+use super::deflate::DeflateConfig;
+use super::frame::Role;
+use super::random;
+use crate::api;
+use crate::http::{self, HttpHead};
+use base64::Engine;
+use std::io::{Read, Write};
+
+/// Appended to the key before hashing it, as described in RFC 6455 section 1.3.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The most that will be read while waiting for the end of a request or response head.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// The result of a successful opening handshake.
+pub struct Handshake {
+    /// The other side's request or response.
+    pub head: HttpHead,
+    /// Anything that arrived after the head, which is the start of the first frame.
+    pub leftover: Vec<u8>,
+    /// The `permessage-deflate` parameters, if compression was negotiated.
+    pub deflate: Option<DeflateConfig>,
+    /// The subprotocol that was chosen, if any.
+    pub protocol: Option<String>,
+}
+
+/// Ways that the handshake can fail.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The connection failed, or the response wasn't HTTP.
+    Failed,
+    /// The response was HTTP, but the status wasn't 101.
+    BadStatus(u16, HttpHead),
+    /// The response didn't include `Upgrade: websocket` or `Connection: Upgrade`.
+    MissingHeader,
+    /// `Sec-WebSocket-Accept` didn't match the key that was sent.
+    InvalidAccept,
+    /// An incoming request wasn't a valid websocket upgrade, and was refused.
+    Refused,
+    /// The response accepted an extension that wasn't offered, or with parameters that
+    /// can't be used.
+    InvalidExtension,
+    /// The response chose a subprotocol that wasn't offered.
+    InvalidProtocol,
+}
+
+impl HandshakeError {
+    pub fn to_api_error(&self) -> api::Error {
+        match self {
+            HandshakeError::Failed => api::Error::HandshakeFailed,
+            HandshakeError::BadStatus(..) => api::Error::BadStatus,
+            HandshakeError::MissingHeader => api::Error::MissingHeader,
+            HandshakeError::InvalidAccept => api::Error::InvalidAccept,
+            HandshakeError::Refused => api::Error::HandshakeFailed,
+            HandshakeError::InvalidExtension => api::Error::InvalidExtension,
+            HandshakeError::InvalidProtocol => api::Error::InvalidProtocol,
+        }
+    }
+}
+
+/// The value that `Sec-WebSocket-Accept` must have for a given `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes())
+}
+
+/// Send the upgrade request described by `request` and check the response.
+pub fn client_handshake<S: Read + Write>(
+    stream: &mut S,
+    request: &OpenRequest,
+) -> Result<Handshake, HandshakeError> {
+    let url = &request.url;
+    let mut nonce = [0u8; 16];
+    random::fill(&mut nonce);
+    let key = base64::engine::general_purpose::STANDARD.encode(nonce);
+
+    let mut head = HttpHead {
+        start_line: format!("GET {} HTTP/1.1", url.path),
+        headers: vec![
+            ("Host".to_owned(), url.host_header.clone()),
+            ("Upgrade".to_owned(), "websocket".to_owned()),
+            ("Connection".to_owned(), "Upgrade".to_owned()),
+            ("Sec-WebSocket-Key".to_owned(), key.clone()),
+            ("Sec-WebSocket-Version".to_owned(), "13".to_owned()),
+        ],
+    };
+    if request.compression {
+        head.headers.push((
+            "Sec-WebSocket-Extensions".to_owned(),
+            DeflateConfig::offer(),
+        ));
+    }
+    if !request.protocols.is_empty() {
+        head.headers.push((
+            "Sec-WebSocket-Protocol".to_owned(),
+            request.protocols.join(", "),
+        ));
+    }
+    head.headers.extend(request.headers.iter().cloned());
+    stream
+        .write_all(&head.to_bytes())
+        .map_err(|_| HandshakeError::Failed)?;
+
+    let (head, rest) = read_head(stream)?;
+    match head.status() {
+        Some(101) => (),
+        Some(status) => return Err(HandshakeError::BadStatus(status, head)),
+        None => return Err(HandshakeError::Failed),
+    }
+    if !head
+        .header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+        || !head.header_has_token("Connection", "upgrade")
+    {
+        return Err(HandshakeError::MissingHeader);
+    }
+    if head.header("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(HandshakeError::InvalidAccept);
+    }
+
+    // The server may accept at most the one extension that was offered.
+    let extensions = extensions(&head);
+    let deflate = match extensions.as_slice() {
+        [] => None,
+        [element] if request.compression => match DeflateConfig::parse(element) {
+            Some(config) if config.can_send(Role::Client) => Some(config),
+            _ => return Err(HandshakeError::InvalidExtension),
+        },
+        _ => return Err(HandshakeError::InvalidExtension),
+    };
+
+    // The server may choose at most one of the subprotocols that were offered.
+    let protocol = head.header("Sec-WebSocket-Protocol").map(|p| p.to_owned());
+    if protocol
+        .as_ref()
+        .is_some_and(|p| !request.protocols.contains(p))
+    {
+        return Err(HandshakeError::InvalidProtocol);
+    }
+    Ok(Handshake {
+        head,
+        leftover: rest,
+        deflate,
+        protocol,
+    })
+}
+
+/// Every element of every `Sec-WebSocket-Extensions` header in `head`.
+fn extensions(head: &HttpHead) -> Vec<&str> {
+    head.headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Sec-WebSocket-Extensions"))
+        .flat_map(|(_, value)| http::split_list(value))
+        .collect()
+}
+
+/// Read an upgrade request from a client and answer it. If `compression` is set, the first
+/// usable `permessage-deflate` offer is accepted. If the client offers subprotocols, they
+/// are passed to `select`, which may choose one of them. Requests that aren't valid
+/// upgrades are answered with an error status before this returns.
+pub fn server_handshake<S: Read + Write>(
+    stream: &mut S,
+    compression: bool,
+    select: impl FnOnce(&[&str]) -> Option<String>,
+) -> Result<Handshake, HandshakeError> {
+    let (head, rest) = read_head(stream)?;
+    let mut deflate = None;
+    let mut protocol = None;
+    let response = match check_request(&head) {
+        Ok(key) => {
+            let mut response = HttpHead {
+                start_line: "HTTP/1.1 101 Switching Protocols".to_owned(),
+                headers: vec![
+                    ("Upgrade".to_owned(), "websocket".to_owned()),
+                    ("Connection".to_owned(), "Upgrade".to_owned()),
+                    ("Sec-WebSocket-Accept".to_owned(), accept_key(key)),
+                ],
+            };
+            if compression {
+                deflate = extensions(&head)
+                    .into_iter()
+                    .filter_map(DeflateConfig::parse)
+                    .find(|config| config.can_send(Role::Server));
+            }
+            if let Some(config) = &deflate {
+                response
+                    .headers
+                    .push(("Sec-WebSocket-Extensions".to_owned(), config.to_header()));
+            }
+            let offers: Vec<&str> = head
+                .headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("Sec-WebSocket-Protocol"))
+                .flat_map(|(_, value)| http::split_list(value))
+                .collect();
+            if !offers.is_empty() {
+                // Anything other than one of the offers would make the client fail the
+                // connection, so it's treated as choosing nothing.
+                protocol = select(&offers).filter(|p| offers.contains(&p.as_str()));
+            }
+            if let Some(protocol) = &protocol {
+                response
+                    .headers
+                    .push(("Sec-WebSocket-Protocol".to_owned(), protocol.clone()));
+            }
+            response
+        }
+        // RFC 6455 section 4.2.2 asks for the supported version to be listed when the
+        // client asks for a different one.
+        Err(426) => HttpHead {
+            start_line: "HTTP/1.1 426 Upgrade Required".to_owned(),
+            headers: vec![("Sec-WebSocket-Version".to_owned(), "13".to_owned())],
+        },
+        Err(_) => HttpHead {
+            start_line: "HTTP/1.1 400 Bad Request".to_owned(),
+            headers: vec![("Content-Length".to_owned(), "0".to_owned())],
+        },
+    };
+    stream
+        .write_all(&response.to_bytes())
+        .map_err(|_| HandshakeError::Failed)?;
+    match response.status() {
+        Some(101) => Ok(Handshake {
+            head,
+            leftover: rest,
+            deflate,
+            protocol,
+        }),
+        _ => Err(HandshakeError::Refused),
+    }
+}
+
+/// Check that `head` is a websocket upgrade request, as described in RFC 6455 section
+/// 4.2.1, and return its key. Otherwise return the status to refuse it with.
+fn check_request(head: &HttpHead) -> Result<&str, u16> {
+    let mut parts = head.start_line.split(' ');
+    let (Some("GET"), Some(_), Some("HTTP/1.1")) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(400);
+    };
+    if !head
+        .header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+        || !head.header_has_token("Connection", "upgrade")
+        || head.header("Host").is_none()
+    {
+        return Err(400);
+    }
+    if head.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(426);
+    }
+    // The key is a base64-encoded 16-byte nonce.
+    let key = head.header("Sec-WebSocket-Key").ok_or(400u16)?;
+    match base64::engine::general_purpose::STANDARD.decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(key),
+        _ => Err(400),
+    }
+}
+
+/// Read until the end of an HTTP head, and return it along with anything that followed.
+fn read_head<S: Read>(stream: &mut S) -> Result<(HttpHead, Vec<u8>), HandshakeError> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        if let Some(len) = http::head_len(&received) {
+            let head = HttpHead::parse(&received[..len]).ok_or(HandshakeError::Failed)?;
+            return Ok((head, received.split_off(len)));
+        }
+        if received.len() > MAX_HEAD_SIZE {
+            return Err(HandshakeError::Failed);
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => return Err(HandshakeError::Failed),
+            Ok(len) => received.extend_from_slice(&buffer[..len]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_accept_key() {
+        // RFC 6455 section 1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Keepalive pings, which find connections whose other side has gone away without closing
+//! them. The server sends itself a `Tick` every `TICK_INTERVAL`, and each tick checks every
+//! open connection.
+
+use std::time::{Duration, Instant};
+
+/// How often the server ticks. Keepalive intervals are rounded up to a whole tick.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The payload of keepalive pings. Pongs that echo it are used up here rather than being
+/// passed on to the owner, who didn't send the ping.
+pub const PING_PAYLOAD: &[u8] = b"keepalive";
+
+/// What a connection needs done on a tick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    Nothing,
+    /// The connection has been idle for a whole interval, so it should be pinged.
+    Ping,
+    /// Too many pings went unanswered, and the connection should be failed.
+    Dead,
+}
+
+/// Keepalive state for one connection.
+pub struct KeepAlive {
+    /// How long the connection may be idle before it's pinged, and how long to wait for
+    /// the pong.
+    interval: Duration,
+    /// How many pongs may be missed in a row before the connection is declared dead.
+    max_missed: u8,
+    /// When something last arrived on the connection.
+    last_received: Instant,
+    /// When the outstanding ping was sent, if there is one.
+    ping_sent: Option<Instant>,
+    missed: u8,
+}
+
+impl KeepAlive {
+    pub fn new(config: KeepAliveConfig) -> KeepAlive {
+        KeepAlive {
+            interval: config.interval,
+            max_missed: config.max_missed.max(1),
+            last_received: Instant::now(),
+            ping_sent: None,
+            missed: 0,
+        }
+    }
+
+    /// Note that a frame arrived. Returns `true` if it was the pong for a keepalive ping,
+    /// which shouldn't be passed on.
+    pub fn received(&mut self, pong: Option<&[u8]>) -> bool {
+        self.last_received = Instant::now();
+        match pong {
+            Some(PING_PAYLOAD) if self.ping_sent.is_some() => {
+                self.ping_sent = None;
+                self.missed = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Decide what to do on a tick at `now`.
+    pub fn tick(&mut self, now: Instant) -> Action {
+        // A ping that has gone unanswered for a whole interval counts as missed, and
+        // another one goes out in its place.
+        if let Some(sent) = self.ping_sent {
+            if now.duration_since(sent) < self.interval {
+                return Action::Nothing;
+            }
+            self.ping_sent = None;
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return Action::Dead;
+            }
+        } else if now.duration_since(self.last_received) < self.interval {
+            return Action::Nothing;
+        }
+        self.ping_sent = Some(now);
+        Action::Ping
+    }
+}
+
+/// How a connection should be kept alive, as requested in `Open` or `Listen`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeepAliveConfig {
+    interval: Duration,
+    max_missed: u8,
+}
+
+impl KeepAliveConfig {
+    /// Read the settings from the value built by `api::open_offset()`. Returns `None` if
+    /// keepalive pings are turned off.
+    pub fn from_open_offset(offset: usize) -> Option<KeepAliveConfig> {
+        let (_, interval, max_missed) = crate::api::parse_open_offset(offset);
+        (interval != 0).then(|| KeepAliveConfig {
+            interval: Duration::from_secs(interval as u64),
+            max_missed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn keepalive(interval: u16, max_missed: u8) -> KeepAlive {
+        let offset = crate::api::open_offset(0, interval, max_missed);
+        KeepAlive::new(KeepAliveConfig::from_open_offset(offset).unwrap())
+    }
+
+    #[test]
+    fn pings_when_idle() {
+        let mut keepalive = keepalive(5, 3);
+        let start = Instant::now();
+        assert_eq!(keepalive.tick(start + SECOND), Action::Nothing);
+        assert_eq!(keepalive.tick(start + 5 * SECOND), Action::Ping);
+        // Nothing more goes out while the ping is waiting for its pong.
+        assert_eq!(keepalive.tick(start + 6 * SECOND), Action::Nothing);
+
+        // The pong is used up, and the connection counts as idle again from then.
+        assert!(keepalive.received(Some(PING_PAYLOAD)));
+        let answered = Instant::now();
+        assert_eq!(keepalive.tick(answered + 4 * SECOND), Action::Nothing);
+        assert_eq!(keepalive.tick(answered + 5 * SECOND), Action::Ping);
+    }
+
+    #[test]
+    fn other_frames_are_passed_on() {
+        let mut keepalive = keepalive(5, 3);
+        // A pong that nobody asked for belongs to the owner.
+        assert!(!keepalive.received(Some(PING_PAYLOAD)));
+        let start = Instant::now();
+        assert_eq!(keepalive.tick(start + 5 * SECOND), Action::Ping);
+        assert!(!keepalive.received(None));
+        assert!(!keepalive.received(Some(b"something else")));
+        assert!(keepalive.received(Some(PING_PAYLOAD)));
+    }
+
+    #[test]
+    fn dead_after_max_missed() {
+        let mut keepalive = keepalive(5, 3);
+        let start = Instant::now();
+        assert_eq!(keepalive.tick(start + 5 * SECOND), Action::Ping);
+        // Each unanswered ping is replaced by another, until too many have been missed.
+        assert_eq!(keepalive.tick(start + 10 * SECOND), Action::Ping);
+        assert_eq!(keepalive.tick(start + 15 * SECOND), Action::Ping);
+        assert_eq!(keepalive.tick(start + 20 * SECOND), Action::Dead);
+    }
+
+    #[test]
+    fn pong_resets_missed_count() {
+        let mut keepalive = keepalive(5, 2);
+        let start = Instant::now();
+        assert_eq!(keepalive.tick(start + 5 * SECOND), Action::Ping);
+        assert_eq!(keepalive.tick(start + 10 * SECOND), Action::Ping);
+        assert!(keepalive.received(Some(PING_PAYLOAD)));
+
+        // One more miss would have been fatal, but the pong started the count again.
+        let answered = Instant::now();
+        assert_eq!(keepalive.tick(answered + 5 * SECOND), Action::Ping);
+        assert_eq!(keepalive.tick(answered + 10 * SECOND), Action::Ping);
+        assert_eq!(keepalive.tick(answered + 15 * SECOND), Action::Dead);
+    }
+
+    #[test]
+    fn off_without_an_interval() {
+        assert_eq!(
+            KeepAliveConfig::from_open_offset(crate::api::open_offset(0, 0, 3)),
+            None
+        );
+        // Missing no pongs at all isn't allowed, so it counts as missing one.
+        let mut keepalive = keepalive(1, 0);
+        let start = Instant::now();
+        assert_eq!(keepalive.tick(start + SECOND), Action::Ping);
+        assert_eq!(keepalive.tick(start + 2 * SECOND), Action::Dead);
+    }
+}
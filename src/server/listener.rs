@@ -0,0 +1,185 @@
+use super::connection;
+use super::deflate::Deflate;
+use super::frame::Role;
+use super::handshake;
+use super::keepalive::{KeepAlive, KeepAliveConfig};
+use super::{Connection, ServerState, WebSocketFd};
+use crate::api;
+use api::ConnectionState;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// How often to check whether the listener has been closed while waiting for connections.
+/// `TcpListener` can't be woken from a blocking `accept()`, so it's polled instead.
+const ACCEPT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// How long a client has to send its upgrade request after connecting.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A thread that accepts connections on `listener` until the listener is closed. Each
+/// connection gets a `WebSocketFd` of its own and a thread to perform the handshake.
+pub fn listener_thread(state: Arc<Mutex<ServerState>>, fd: WebSocketFd, listener: TcpListener) {
+    if listener.set_nonblocking(true).is_err() {
+        state
+            .lock()
+            .unwrap()
+            .closed(fd, api::close_code::ABNORMAL, "");
+        return;
+    }
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if !state.lock().unwrap().listeners.contains_key(&fd) {
+                    return;
+                }
+                std::thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                println!("websocket: listener {:?} failed: {}", fd, e);
+                state
+                    .lock()
+                    .unwrap()
+                    .closed(fd, api::close_code::ABNORMAL, "");
+                return;
+            }
+        };
+
+        // Connections are owned by whoever owns the listener.
+        let (new_fd, compression, keepalive) = {
+            let mut state = state.lock().unwrap();
+            let Some((owner, compression, keepalive)) = state
+                .listeners
+                .get(&fd)
+                .map(|l| (l.owner, l.compression, l.keepalive))
+            else {
+                return;
+            };
+            let Some(new_fd) = state.allocate_fd() else {
+                // Dropping the stream refuses the connection.
+                continue;
+            };
+            state.connections.insert(
+                new_fd,
+                Connection {
+                    owner,
+                    state: ConnectionState::Connecting,
+                    writer: None,
+                    failure: None,
+                    closing_since: None,
+                    keepalive: None,
+                    protocol_reply: None,
+                },
+            );
+            (new_fd, compression, keepalive)
+        };
+
+        let state = state.clone();
+        std::thread::spawn(move || {
+            accepted_thread(state, fd, new_fd, stream, compression, keepalive)
+        });
+    }
+}
+
+/// A thread that owns a single accepted connection. It performs the server side of the
+/// opening handshake, tells the owner about the connection, and then reads from it until
+/// it closes.
+fn accepted_thread(
+    state: Arc<Mutex<ServerState>>,
+    listener_fd: WebSocketFd,
+    fd: WebSocketFd,
+    mut stream: TcpStream,
+    compression: bool,
+    keepalive: Option<KeepAliveConfig>,
+) {
+    let select = |offers: &[&str]| select_protocol(&state, listener_fd, fd, offers);
+
+    // Accepted sockets may inherit non-blocking mode from the listener.
+    stream.set_nonblocking(false).ok();
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+    let handshake = match handshake::server_handshake(&mut stream, compression, select) {
+        Ok(result) => result,
+        Err(e) => {
+            println!("websocket: refused incoming connection: {:?}", e);
+            state.lock().unwrap().connections.remove(&fd);
+            return;
+        }
+    };
+    stream.set_read_timeout(None).ok();
+
+    let writer = {
+        let mut state = state.lock().unwrap();
+        // If the listener has been closed in the meantime, there's nobody to tell about
+        // this connection.
+        if !state.listeners.contains_key(&listener_fd) {
+            state.connections.remove(&fd);
+            return;
+        }
+        let Some(connection) = state.connections.get_mut(&fd) else {
+            return;
+        };
+        let deflate = handshake
+            .deflate
+            .map(|config| Deflate::new(config, Role::Server));
+        let writer = match super::make_writer(&stream, Role::Server, None, deflate) {
+            Ok(writer) if connection.transition(ConnectionState::Open).is_ok() => writer,
+            _ => {
+                state.connections.remove(&fd);
+                return;
+            }
+        };
+        connection.writer = Some(writer.clone());
+        connection.keepalive = keepalive.map(KeepAlive::new);
+
+        // The new fd goes first, followed by the request so the owner can see the path
+        // and headers, and then the subprotocol.
+        let mut payload = fd.0.to_be_bytes().to_vec();
+        payload.extend_from_slice(&handshake.head.to_bytes());
+        if let Some(protocol) = &handshake.protocol {
+            payload.extend_from_slice(protocol.as_bytes());
+        }
+        state.deliver(
+            listener_fd,
+            api::MessageKind::Binary,
+            api::POLL_FLAG_ACCEPTED,
+            payload,
+        );
+        writer
+    };
+
+    connection::run(state, fd, stream, writer, &handshake.leftover);
+}
+
+/// Ask the owner of the listener to choose one of the subprotocols that the client behind
+/// `fd` offered, and wait for its `Select` message. Nothing is chosen if the listener
+/// doesn't ask for this, or if the owner takes longer than the handshake is allowed to.
+fn select_protocol(
+    state: &Mutex<ServerState>,
+    listener_fd: WebSocketFd,
+    fd: WebSocketFd,
+    offers: &[&str],
+) -> Option<String> {
+    let (reply, choice) = mpsc::channel();
+    {
+        let mut state = state.lock().unwrap();
+        if !state.listeners.get(&listener_fd)?.select_protocol {
+            return None;
+        }
+        state.connections.get_mut(&fd)?.protocol_reply = Some(reply);
+        let mut payload = fd.0.to_be_bytes().to_vec();
+        payload.extend_from_slice(offers.join(", ").as_bytes());
+        state.deliver(
+            listener_fd,
+            api::MessageKind::Binary,
+            api::POLL_FLAG_SELECT,
+            payload,
+        );
+    }
+    let protocol = choice.recv_timeout(HANDSHAKE_TIMEOUT).ok().flatten();
+    // Stop a late `Select` from being mistaken for an answer.
+    if let Some(connection) = state.lock().unwrap().connections.get_mut(&fd) {
+        connection.protocol_reply = None;
+    }
+    protocol
+}
@@ -0,0 +1,827 @@
+mod connection;
+mod deflate;
+mod frame;
+mod handshake;
+mod keepalive;
+mod listener;
+mod random;
+mod tls;
+
+use crate::api;
+use api::ConnectionState;
+use deflate::Deflate;
+use frame::{Frame, Opcode, Role};
+use keepalive::{KeepAlive, KeepAliveConfig};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// How long a write may block before the connection is given up on. Messages are sent on
+/// the thread that handles every request, so a peer that stops reading mustn't be able to
+/// hold it up for long.
+const WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How much data may be queued for a process that isn't polling quickly enough. Once this
+/// much is waiting, a connection that receives more is failed with `POLICY_VIOLATION`
+/// rather than letting the queue grow without bound.
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long to wait for the other side to answer a close frame, or to close the TCP
+/// connection after answering one, before dropping the connection anyway.
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The server-side handle for a connection. Clients refer to connections by this number.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct WebSocketFd(u16);
+
+impl WebSocketFd {
+    pub fn as_usize(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// An entry in the connection table.
+struct Connection {
+    /// The process that opened this connection. Incoming data is only ever handed to
+    /// `Poll` requests from this process.
+    owner: xous::PID,
+    state: ConnectionState,
+    /// Used for everything that's written to the connection, once it's open. It's cloned
+    /// out of the table and the table is unlocked before it's locked, so that a peer that's
+    /// slow to read only holds up writes to itself.
+    writer: Option<Arc<Mutex<Writer>>>,
+    /// The code and reason to report to the owner once the connection has closed, if the
+    /// server failed the connection itself.
+    failure: Option<(u16, String)>,
+    /// When the connection moved to `Closing`, so that it can be dropped if the closing
+    /// handshake never finishes.
+    closing_since: Option<std::time::Instant>,
+    /// Keepalive state, once the connection is open, unless pings were turned off.
+    keepalive: Option<KeepAlive>,
+    /// Set while an accepted connection waits for its owner to choose a subprotocol. The
+    /// choice from the `Select` message is sent here.
+    protocol_reply: Option<mpsc::Sender<Option<String>>>,
+}
+
+impl Connection {
+    /// Move the connection to a new state, refusing any transition that
+    /// `ConnectionState` doesn't allow.
+    fn transition(&mut self, next: ConnectionState) -> Result<(), api::Error> {
+        if !self.state.can_transition_to(next) {
+            return Err(api::Error::InvalidState);
+        }
+        if next == ConnectionState::Closing {
+            self.closing_since = Some(std::time::Instant::now());
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    /// The handle for writing to the connection, which it doesn't have until it's open.
+    fn writer(&self) -> Result<Arc<Mutex<Writer>>, api::Error> {
+        self.writer.clone().ok_or(api::Error::SendFailed)
+    }
+}
+
+/// The parts of an open connection that are needed to write to it.
+struct Writer {
+    /// A handle to the socket. The connection thread has its own clone that it uses for
+    /// reading.
+    socket: TcpStream,
+    /// Whether this end dialed out or accepted the connection.
+    role: Role,
+    /// The TLS session for `wss://` connections. Everything written to `socket` goes
+    /// through this, and everything read from it is decrypted by it.
+    tls: Option<rustls::ClientConnection>,
+    /// Compression state, if `permessage-deflate` was negotiated. Incoming messages are
+    /// inflated with this too.
+    deflate: Option<Deflate>,
+    /// Set once a close frame has been written, after which nothing else may be.
+    close_sent: bool,
+}
+
+impl Writer {
+    /// Send a whole message as a single frame, compressing it if `permessage-deflate` was
+    /// negotiated. Control frames are never compressed.
+    fn send_message(&mut self, opcode: Opcode, data: &[u8]) -> Result<(), api::Error> {
+        let frame = match &mut self.deflate {
+            Some(deflate) if !opcode.is_control() => Frame {
+                rsv: frame::RSV1,
+                ..Frame::new(opcode, deflate.compress(data))
+            },
+            _ => Frame::new(opcode, data.to_vec()),
+        };
+        self.write_frame(&frame)
+    }
+
+    /// Write a frame to the socket. Frames sent by a client must be masked, and frames
+    /// sent by a server must not be.
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), api::Error> {
+        // The connection's state is checked before its writer is locked, so a frame that
+        // was about to be sent may lose the race with a close frame.
+        if self.close_sent {
+            return Err(api::Error::InvalidState);
+        }
+        self.close_sent = frame.opcode == Opcode::Close;
+        let mask = match self.role {
+            Role::Client => {
+                let mut mask = [0u8; 4];
+                random::fill(&mut mask);
+                Some(mask)
+            }
+            Role::Server => None,
+        };
+        let result = match &mut self.tls {
+            Some(session) => tls::send(session, &self.socket, &frame.encode(mask)),
+            None => self.socket.write_all(&frame.encode(mask)),
+        };
+        // Part of the frame may have gone out, so nothing more can be written after it.
+        // The connection thread notices once the socket is shut down, and reports that the
+        // connection dropped.
+        if result.is_err() {
+            self.shutdown();
+        }
+        result.map_err(|_| api::Error::SendFailed)
+    }
+
+    /// Stop reading from and writing to the socket. The connection thread notices, and
+    /// reports that the connection has closed.
+    fn shutdown(&self) {
+        self.socket.shutdown(Shutdown::Both).ok();
+    }
+}
+
+/// Make the handle that a connection uses for writing, once its opening handshake is done.
+fn make_writer(
+    stream: &TcpStream,
+    role: Role,
+    tls: Option<rustls::ClientConnection>,
+    deflate: Option<Deflate>,
+) -> std::io::Result<Arc<Mutex<Writer>>> {
+    let socket = stream.try_clone()?;
+    socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    Ok(Arc::new(Mutex::new(Writer {
+        socket,
+        role,
+        tls,
+        deflate,
+        close_sent: false,
+    })))
+}
+
+/// What's left to do to fail a connection once the connection table has been unlocked:
+/// send a close frame, if there is one to send, and then shut the socket down.
+#[must_use]
+struct Failure {
+    writer: Arc<Mutex<Writer>>,
+    close: Option<Frame>,
+}
+
+impl Failure {
+    fn send(self) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(close) = &self.close {
+            writer.write_frame(close).ok();
+        }
+        writer.shutdown();
+    }
+}
+
+/// Fail the connection, as `ServerState::fail()` does, and send the close frame once
+/// `state` has been unlocked.
+fn fail(state: &Mutex<ServerState>, fd: WebSocketFd, code: u16, reason: &str) {
+    let failure = state.lock().unwrap().fail(fd, code, reason);
+    if let Some(failure) = failure {
+        failure.send();
+    }
+}
+
+/// A socket that is accepting connections on behalf of a client.
+struct Listener {
+    /// The process that asked for the listener. It owns every connection that is accepted.
+    owner: xous::PID,
+    /// Whether to accept `permessage-deflate` when a client offers it.
+    compression: bool,
+    /// Whether to ask the owner to choose a subprotocol when a client offers some.
+    select_protocol: bool,
+    /// How to keep accepted connections alive.
+    keepalive: Option<KeepAliveConfig>,
+}
+
+/// Data that has arrived on a connection, but hasn't yet been handed to a client.
+struct Incoming {
+    fd: WebSocketFd,
+    kind: api::MessageKind,
+    /// `POLL_FLAG_*` bits that are passed back along with the fd.
+    flags: usize,
+    data: Vec<u8>,
+}
+
+/// Per-process bookkeeping. Each client process has a poll thread that keeps a `Poll` request
+/// outstanding, and any data that arrives while no `Poll` is outstanding gets queued here.
+#[derive(Default)]
+struct Client {
+    polls: VecDeque<xous::MessageEnvelope>,
+    pending: VecDeque<Incoming>,
+    /// The number of bytes of data in `pending`.
+    pending_bytes: usize,
+}
+
+#[derive(Default)]
+pub struct ServerState {
+    connections: HashMap<WebSocketFd, Connection>,
+    /// Listeners share the `WebSocketFd` space with connections.
+    listeners: HashMap<WebSocketFd, Listener>,
+    clients: HashMap<xous::PID, Client>,
+    /// Processes that sent `Disconnect` while they had no `Poll` outstanding. Their poll
+    /// thread's next `Poll` is answered with `POLL_FLAG_SHUTDOWN` straight away.
+    disconnected: HashSet<xous::PID>,
+    last_fd: u16,
+}
+
+impl ServerState {
+    /// Find an unused `WebSocketFd`. These start at 1, since 0 can't be represented in
+    /// the `offset` field of a response.
+    fn allocate_fd(&mut self) -> Option<WebSocketFd> {
+        for _ in 0..u16::MAX {
+            self.last_fd = self.last_fd.checked_add(1).unwrap_or(1);
+            let fd = WebSocketFd(self.last_fd);
+            if !self.connections.contains_key(&fd) && !self.listeners.contains_key(&fd) {
+                return Some(fd);
+            }
+        }
+        None
+    }
+
+    /// Hand data to the process that owns `fd`. If that process has a `Poll` outstanding then
+    /// the data is returned immediately, otherwise it's queued until the next `Poll` comes in.
+    fn deliver(&mut self, fd: WebSocketFd, kind: api::MessageKind, flags: usize, data: Vec<u8>) {
+        let owner = match self.connections.get(&fd) {
+            Some(connection) => connection.owner,
+            None => match self.listeners.get(&fd) {
+                Some(listener) => listener.owner,
+                None => return,
+            },
+        };
+        let client = self.clients.entry(owner).or_default();
+        client.pending_bytes += data.len();
+        client.pending.push_back(Incoming {
+            fd,
+            kind,
+            flags,
+            data,
+        });
+        client.flush();
+    }
+
+    /// Whether the process that owns `fd` has `MAX_PENDING_BYTES` or more waiting for it.
+    /// This only holds back data from connections, since close notifications always have
+    /// to go out.
+    fn backlogged(&self, fd: WebSocketFd) -> bool {
+        self.connections
+            .get(&fd)
+            .and_then(|connection| self.clients.get(&connection.owner))
+            .is_some_and(|client| client.pending_bytes >= MAX_PENDING_BYTES)
+    }
+
+    /// Fail the connection as described in RFC 6455 section 7.1.7. A close frame is sent
+    /// if one hasn't been already and `code` may be sent, and nothing more is read. Once
+    /// the connection thread notices, the owner is told that it closed with `code` and
+    /// `reason`. Writing the close frame may block, so that's returned to be done once
+    /// the table is unlocked.
+    fn fail(&mut self, fd: WebSocketFd, code: u16, reason: &str) -> Option<Failure> {
+        let connection = self.connections.get_mut(&fd)?;
+        let mut close = None;
+        if connection.state == ConnectionState::Open && api::close_code::is_valid(code) {
+            connection.transition(ConnectionState::Closing).ok();
+            close = Some(Frame::close(code, reason));
+        }
+        connection.failure = Some((code, reason.to_owned()));
+        // The socket is about to be shut down, so there's no closing handshake to wait for.
+        connection.closing_since = None;
+        Some(Failure {
+            writer: connection.writer.clone()?,
+            close,
+        })
+    }
+
+    /// Close every connection and listener that `owner` has, or everybody's if `owner` is
+    /// `None`, and return the outstanding `Poll`s with `POLL_FLAG_SHUTDOWN` so that the
+    /// poll threads wake up. Open connections are to be sent a close frame with
+    /// `GOING_AWAY`, which is returned to be done once the table is unlocked.
+    fn disconnect(&mut self, owner: Option<xous::PID>) -> Vec<Failure> {
+        let owned = |pid: xous::PID| owner.is_none_or(|owner| owner == pid);
+        let fds: Vec<WebSocketFd> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| owned(connection.owner))
+            .map(|(fd, _)| *fd)
+            .collect();
+        let mut failures = Vec::new();
+        for fd in fds {
+            failures.extend(self.fail(fd, api::close_code::GOING_AWAY, ""));
+            // The owner won't be polling any more, so the connection thread has nobody to
+            // report to. It notices that the connection is gone and exits.
+            self.connections.remove(&fd);
+        }
+        self.listeners.retain(|_, listener| !owned(listener.owner));
+        // A poll thread that's between `Poll`s would otherwise wait forever on its next one.
+        if let Some(owner) = owner {
+            if self
+                .clients
+                .get(&owner)
+                .is_none_or(|client| client.polls.is_empty())
+            {
+                self.disconnected.insert(owner);
+            }
+        }
+        self.clients.retain(|pid, client| {
+            if !owned(*pid) {
+                return true;
+            }
+            // Dropping each envelope returns its page to the client.
+            for mut poll in client.polls.drain(..) {
+                set_memory_response(&mut poll, api::POLL_FLAG_SHUTDOWN, 0);
+            }
+            false
+        });
+        failures
+    }
+
+    /// Tell the owner that the connection or listener has closed, and remove it from the
+    /// table. The close notification carries the same payload as a close frame: a
+    /// big-endian code followed by the reason.
+    fn closed(&mut self, fd: WebSocketFd, code: u16, reason: &str) {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.deliver(fd, api::MessageKind::Close, api::POLL_FLAG_CLOSED, payload);
+        if let Some(mut connection) = self.connections.remove(&fd) {
+            // Every state may move to `Closed`, so this can't fail.
+            connection.transition(ConnectionState::Closed).ok();
+        }
+        self.listeners.remove(&fd);
+    }
+}
+
+impl Client {
+    /// Hand queued data to outstanding `Poll` requests until one or the other runs out.
+    fn flush(&mut self) {
+        while !self.pending.is_empty() && !self.polls.is_empty() {
+            let poll = self.polls.pop_front().unwrap();
+            let incoming = self.pending.pop_front().unwrap();
+            self.pending_bytes -= incoming.data.len();
+            // Anything that didn't fit goes back to the front of the queue, so that it's
+            // the next thing to go out.
+            if let Some(rest) = respond_to_poll(poll, incoming) {
+                self.pending_bytes += rest.data.len();
+                self.pending.push_front(rest);
+            }
+        }
+    }
+}
+
+/// Fill a `Poll` buffer and return it to the client. The `offset` field holds the
+/// `WebSocketFd`, kind and flags, and the `valid` field holds the number of bytes.
+///
+/// If `incoming` is larger than the buffer, as much as fits is sent with `POLL_FLAG_MORE`
+/// set, and the remainder is returned so it can go out in the next `Poll`. The flags in
+/// `incoming` are only sent along with the final piece, but every piece carries the kind.
+fn respond_to_poll(mut poll: xous::MessageEnvelope, mut incoming: Incoming) -> Option<Incoming> {
+    let mut rest = None;
+    if let Some(mem) = poll.body.memory_message_mut() {
+        // Safety: any bit pattern is a valid `u8`.
+        let buffer = unsafe { mem.buf.as_slice_mut::<u8>() };
+        let mut flags = incoming.flags;
+        if incoming.data.len() > buffer.len() {
+            flags = api::POLL_FLAG_MORE;
+            rest = Some(Incoming {
+                fd: incoming.fd,
+                kind: incoming.kind,
+                flags: incoming.flags,
+                data: incoming.data.split_off(buffer.len()),
+            });
+        }
+        let len = incoming.data.len();
+        buffer[..len].copy_from_slice(&incoming.data);
+        mem.offset =
+            xous::MemoryAddress::new(api::poll_offset(incoming.fd.0, incoming.kind, flags));
+        mem.valid = xous::MemorySize::new(len);
+    }
+    // Dropping the envelope returns the memory to the client.
+    rest
+}
+
+/// The part of a memory message that the sender marked as valid.
+fn valid_bytes(mem: &xous::MemoryMessage) -> &[u8] {
+    let len = mem.valid.map(|v| v.get()).unwrap_or(0).min(mem.buf.len());
+    // Safety: any bit pattern is a valid `u8`.
+    unsafe { &mem.buf.as_slice::<u8>()[..len] }
+}
+
+/// Set the values that are passed back to the client in a memory response. The memory
+/// itself is returned when the envelope is dropped.
+fn set_memory_response(msg: &mut xous::MessageEnvelope, offset: usize, valid: usize) {
+    if let xous::Message::MutableBorrow(mem) | xous::Message::Borrow(mem) = &mut msg.body {
+        mem.offset = xous::MemoryAddress::new(offset);
+        mem.valid = xous::MemorySize::new(valid);
+    }
+}
+
+/// Store an error code in a memory response.
+fn fail_memory(msg: &mut xous::MessageEnvelope, error: api::Error) {
+    set_memory_response(msg, 0, error as usize);
+}
+
+/// Respond to a blocking scalar. Non-blocking scalars don't expect a response, so this is
+/// a no-op for them. The client may have gone away in the meantime, which isn't the
+/// server's problem.
+fn return_scalar(msg: &xous::MessageEnvelope, value: usize) {
+    if let xous::Message::BlockingScalar(_) = msg.body {
+        if let Err(e) = xous::return_scalar(msg.sender, value) {
+            println!("websocket: couldn't return scalar: {:?}", e);
+        }
+    }
+}
+
+/// Respond to a blocking scalar with two values.
+fn return_scalar2(msg: &xous::MessageEnvelope, value1: usize, value2: usize) {
+    if let xous::Message::BlockingScalar(_) = msg.body {
+        if let Err(e) = xous::return_scalar2(msg.sender, value1, value2) {
+            println!("websocket: couldn't return scalar: {:?}", e);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Server {
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl Server {
+    /// Receive messages from clients and dispatch them. Returns when a `Quit` message
+    /// is received.
+    pub fn run(&self, sid: xous::SID) {
+        // Keepalive pings are driven by ticks that the server sends itself. The thread
+        // exits once the server is gone.
+        let cid = xous::connect(sid).expect("couldn't connect to the websocket server");
+        std::thread::spawn(move || loop {
+            std::thread::sleep(keepalive::TICK_INTERVAL);
+            let tick = xous::Message::new_scalar(api::Opcodes::Tick as usize, 0, 0, 0, 0);
+            if xous::send_message(cid, tick).is_err() {
+                break;
+            }
+        });
+
+        loop {
+            let msg = match xous::receive_message(sid) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    println!("websocket: couldn't receive message: {:?}", e);
+                    continue;
+                }
+            };
+            match api::Opcodes::try_from(msg.body.id()) {
+                Ok(api::Opcodes::Open) => self.open(msg),
+                Ok(api::Opcodes::Send) => self.send(msg),
+                Ok(api::Opcodes::Poll) => self.poll(msg),
+                Ok(api::Opcodes::Close) => self.close(msg),
+                Ok(api::Opcodes::State) => self.connection_state(msg),
+                Ok(api::Opcodes::Tick) => self.tick(),
+                Ok(api::Opcodes::Quit) => {
+                    let failures = self.state.lock().unwrap().disconnect(None);
+                    failures.into_iter().for_each(Failure::send);
+                    return_scalar(&msg, 0);
+                    break;
+                }
+                Ok(api::Opcodes::Listen) => self.listen(msg),
+                Ok(api::Opcodes::Select) => self.select(msg),
+                Ok(api::Opcodes::Disconnect) => self.disconnect(msg),
+                Err(id) => {
+                    println!("websocket: unrecognized opcode {}", id);
+                    return_scalar(&msg, api::Error::InvalidRequest as usize);
+                }
+            }
+        }
+    }
+
+    /// Begin opening a connection. The connection is made on a new thread, which holds
+    /// on to `msg` and returns it once the connection succeeds or fails.
+    fn open(&self, mut msg: xous::MessageEnvelope) {
+        let (Some(owner), xous::Message::MutableBorrow(mem)) = (msg.sender.pid(), &msg.body) else {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return_scalar(&msg, api::Error::InvalidRequest as usize);
+            return;
+        };
+
+        let offset = mem.offset.map(|o| o.get()).unwrap_or(0);
+        let request = match connection::OpenRequest::parse(valid_bytes(mem), offset) {
+            Ok(request) => request,
+            Err(e) => {
+                fail_memory(&mut msg, e);
+                return;
+            }
+        };
+
+        let fd = {
+            let mut state = self.state.lock().unwrap();
+            let Some(fd) = state.allocate_fd() else {
+                fail_memory(&mut msg, api::Error::TooManyConnections);
+                return;
+            };
+            state.connections.insert(
+                fd,
+                Connection {
+                    owner,
+                    state: ConnectionState::Connecting,
+                    writer: None,
+                    failure: None,
+                    closing_since: None,
+                    keepalive: None,
+                    protocol_reply: None,
+                },
+            );
+            fd
+        };
+
+        let state = self.state.clone();
+        std::thread::spawn(move || connection::connection_thread(state, fd, request, msg));
+    }
+
+    /// Start listening for connections on the port in `arg1`. Connections are accepted on
+    /// a new thread, which reports them through `Poll`.
+    fn listen(&self, msg: xous::MessageEnvelope) {
+        let (Some(owner), Some(scalar)) = (msg.sender.pid(), msg.body.scalar_message()) else {
+            return_scalar2(&msg, 0, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let Ok(port) = u16::try_from(scalar.arg1) else {
+            return_scalar2(&msg, 0, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let socket = match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("websocket: couldn't listen on port {}: {}", port, e);
+                return_scalar2(&msg, 0, api::Error::ListenFailed as usize);
+                return;
+            }
+        };
+
+        let fd = {
+            let mut state = self.state.lock().unwrap();
+            let Some(fd) = state.allocate_fd() else {
+                return_scalar2(&msg, 0, api::Error::TooManyConnections as usize);
+                return;
+            };
+            let (flags, _, _) = api::parse_open_offset(scalar.arg2);
+            let listener = Listener {
+                owner,
+                compression: flags & api::OPEN_FLAG_NO_COMPRESSION == 0,
+                select_protocol: flags & api::OPEN_FLAG_SELECT_PROTOCOL != 0,
+                keepalive: KeepAliveConfig::from_open_offset(scalar.arg2),
+            };
+            state.listeners.insert(fd, listener);
+            fd
+        };
+        let state = self.state.clone();
+        std::thread::spawn(move || listener::listener_thread(state, fd, socket));
+        return_scalar2(&msg, fd.as_usize(), 0);
+    }
+
+    /// Ping connections that have been idle for too long, and fail the ones that have
+    /// stopped answering or that haven't finished closing in time.
+    fn tick(&self) {
+        let now = std::time::Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let mut pings = Vec::new();
+        let mut dead = Vec::new();
+        for (fd, connection) in state.connections.iter_mut() {
+            // RFC 6455 section 7.1.1 lets the connection be dropped if the closing
+            // handshake takes too long.
+            if let Some(since) = connection.closing_since {
+                if now.duration_since(since) >= CLOSE_TIMEOUT {
+                    println!("websocket: {:?} didn't finish closing", fd);
+                    dead.push(*fd);
+                }
+            }
+            if connection.state != ConnectionState::Open {
+                continue;
+            }
+            let Some(keepalive) = &mut connection.keepalive else {
+                continue;
+            };
+            match keepalive.tick(now) {
+                keepalive::Action::Nothing => (),
+                keepalive::Action::Ping => pings.extend(connection.writer.clone()),
+                keepalive::Action::Dead => {
+                    println!("websocket: {:?} stopped answering pings", fd);
+                    dead.push(*fd);
+                }
+            }
+        }
+        // There's nobody to send a close frame to, so the connection is just dropped.
+        let failures: Vec<Failure> = dead
+            .into_iter()
+            .filter_map(|fd| state.fail(fd, api::close_code::ABNORMAL, api::TIMEOUT_REASON))
+            .collect();
+        drop(state);
+
+        let ping = Frame::new(Opcode::Ping, keepalive::PING_PAYLOAD.to_vec());
+        for writer in pings {
+            writer.lock().unwrap().write_frame(&ping).ok();
+        }
+        failures.into_iter().for_each(Failure::send);
+    }
+
+    /// Pass on the subprotocol that the owner chose for the accepted connection named in
+    /// `offset`, which is waiting in its handshake.
+    fn select(&self, mut msg: xous::MessageEnvelope) {
+        let (Some(owner), xous::Message::Borrow(mem)) = (msg.sender.pid(), &msg.body) else {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return_scalar(&msg, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let fd = WebSocketFd(mem.offset.map(|o| o.get()).unwrap_or(0) as u16);
+        let protocol = match std::str::from_utf8(valid_bytes(mem)) {
+            Ok("") => None,
+            Ok(protocol) => Some(protocol.to_owned()),
+            Err(_) => {
+                fail_memory(&mut msg, api::Error::InvalidUtf8);
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let reply = match state.connections.get_mut(&fd) {
+            Some(connection) if connection.owner == owner => connection.protocol_reply.take(),
+            _ => None,
+        };
+        drop(state);
+        // The handshake may have given up waiting, in which case the choice is too late.
+        match reply {
+            Some(reply) => {
+                reply.send(protocol).ok();
+                set_memory_response(&mut msg, 0, 0);
+            }
+            None => fail_memory(&mut msg, api::Error::InvalidState),
+        }
+    }
+
+    /// Send the contents of the message as a single frame on the connection named in
+    /// `offset`.
+    fn send(&self, mut msg: xous::MessageEnvelope) {
+        let (Some(owner), xous::Message::Borrow(mem)) = (msg.sender.pid(), &msg.body) else {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return_scalar(&msg, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let (fd, kind) = api::parse_send_offset(mem.offset.map(|o| o.get()).unwrap_or(0));
+        let fd = WebSocketFd(fd);
+        let data = valid_bytes(mem);
+        // Close frames are sent with the `Close` opcode, so that the connection state
+        // follows along.
+        let opcode = match kind {
+            Some(api::MessageKind::Text) => Opcode::Text,
+            Some(api::MessageKind::Binary) => Opcode::Binary,
+            Some(api::MessageKind::Ping) => Opcode::Ping,
+            Some(api::MessageKind::Pong) => Opcode::Pong,
+            Some(api::MessageKind::Close) | None => {
+                fail_memory(&mut msg, api::Error::InvalidRequest);
+                return;
+            }
+        };
+        if opcode.is_control() && data.len() > frame::MAX_CONTROL_PAYLOAD {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return;
+        }
+        if opcode == Opcode::Text && std::str::from_utf8(data).is_err() {
+            fail_memory(&mut msg, api::Error::InvalidUtf8);
+            return;
+        }
+
+        let writer = {
+            let state = self.state.lock().unwrap();
+            match state.connections.get(&fd) {
+                // Data may only be sent once the connection is open, and not after a close
+                // has been requested.
+                Some(connection) if connection.owner == owner => match connection.state {
+                    ConnectionState::Open => connection.writer(),
+                    _ => Err(api::Error::InvalidState),
+                },
+                _ => Err(api::Error::UnknownFd),
+            }
+        };
+        let result = writer.and_then(|writer| {
+            let mut writer = writer.lock().unwrap();
+            writer.send_message(opcode, data).map(|_| data.len())
+        });
+
+        match result {
+            Ok(sent) => set_memory_response(&mut msg, sent, 0),
+            Err(e) => fail_memory(&mut msg, e),
+        }
+    }
+
+    /// Hold on to a `Poll` buffer until there's data for the calling process.
+    fn poll(&self, mut msg: xous::MessageEnvelope) {
+        let (Some(owner), xous::Message::MutableBorrow(_)) = (msg.sender.pid(), &msg.body) else {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return_scalar(&msg, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected.remove(&owner) {
+            set_memory_response(&mut msg, api::POLL_FLAG_SHUTDOWN, 0);
+            return;
+        }
+        let client = state.clients.entry(owner).or_default();
+        client.polls.push_back(msg);
+        client.flush();
+    }
+
+    /// Begin closing the connection named in `offset`. The client is told that the
+    /// connection has closed once the remote side finishes closing it.
+    fn close(&self, mut msg: xous::MessageEnvelope) {
+        let (Some(owner), xous::Message::Borrow(mem)) = (msg.sender.pid(), &msg.body) else {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return_scalar(&msg, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let (fd, code) = api::parse_close_offset(mem.offset.map(|o| o.get()).unwrap_or(0));
+        let fd = WebSocketFd(fd);
+        let reason = valid_bytes(mem);
+        let valid_reason =
+            reason.len() <= api::MAX_CLOSE_REASON && std::str::from_utf8(reason).is_ok();
+        if !api::close_code::is_valid(code) || !valid_reason {
+            fail_memory(&mut msg, api::Error::InvalidRequest);
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // Closing a listener just stops it. Its thread notices and exits, and connections
+        // that it already accepted carry on.
+        if state.listeners.get(&fd).is_some_and(|l| l.owner == owner) {
+            state.listeners.remove(&fd);
+            drop(state);
+            set_memory_response(&mut msg, 0, 0);
+            return;
+        }
+        let writer = match state.connections.get_mut(&fd) {
+            // Closing a connection that is already closing is harmless.
+            Some(connection) if connection.owner == owner => match connection.state {
+                ConnectionState::Closing => Ok(None),
+                // The remote side answers with its own close frame, which the connection
+                // thread picks up.
+                _ => connection
+                    .transition(ConnectionState::Closing)
+                    .and_then(|_| connection.writer())
+                    .map(Some),
+            },
+            _ => Err(api::Error::UnknownFd),
+        };
+        drop(state);
+        let result = writer.and_then(|writer| match writer {
+            Some(writer) => {
+                let reason = std::str::from_utf8(reason).unwrap_or_default();
+                writer
+                    .lock()
+                    .unwrap()
+                    .write_frame(&Frame::close(code, reason))
+            }
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            fail_memory(&mut msg, e);
+        } else {
+            set_memory_response(&mut msg, 0, 0);
+        }
+    }
+
+    /// Close everything that the calling process has open, and wake its poll thread so that
+    /// it can exit.
+    fn disconnect(&self, msg: xous::MessageEnvelope) {
+        let Some(owner) = msg.sender.pid() else {
+            return_scalar(&msg, api::Error::InvalidRequest as usize);
+            return;
+        };
+        let failures = self.state.lock().unwrap().disconnect(Some(owner));
+        failures.into_iter().for_each(Failure::send);
+        return_scalar(&msg, 0);
+    }
+
+    fn connection_state(&self, msg: xous::MessageEnvelope) {
+        let Some(scalar) = msg.body.scalar_message() else {
+            return;
+        };
+        let fd = WebSocketFd(scalar.arg1 as u16);
+        let state = self.state.lock().unwrap();
+        let result = match state.connections.get(&fd) {
+            Some(connection) if Some(connection.owner) == msg.sender.pid() => {
+                connection.state as usize
+            }
+            _ => 0,
+        };
+        return_scalar(&msg, result);
+    }
+}
@@ -0,0 +1,12 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Fill `buf` with unpredictable bytes. The standard library seeds `RandomState` from the
+/// system's entropy source and gives each new instance different keys, so hashing with a
+/// fresh one is enough for frame masks and handshake keys without pulling in an RNG.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let value = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
@@ -0,0 +1,143 @@
+//! TLS for `wss://` connections, using rustls. The handshake is driven on the connection
+//! thread, and afterwards the session lives in the connection's `Writer` next to the
+//! socket, so that reads and writes can share it.
+
+use super::frame::Decoder;
+use crate::api;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{CertificateError, ClientConfig, ClientConnection, RootCertStore};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+
+/// Build the configuration for a connection that trusts `roots`, along with the built-in
+/// roots if `builtin_roots` is set. Most connections use only the built-in roots, and they
+/// share a single configuration.
+pub fn client_config(
+    roots: &[CertificateDer<'static>],
+    builtin_roots: bool,
+) -> Result<Arc<ClientConfig>, api::Error> {
+    static BUILTIN: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if !roots.is_empty() || !builtin_roots {
+        return build_config(roots, builtin_roots);
+    }
+    if let Some(config) = BUILTIN.get() {
+        return Ok(config.clone());
+    }
+    let config = build_config(&[], true)?;
+    Ok(BUILTIN.get_or_init(|| config).clone())
+}
+
+fn build_config(
+    roots: &[CertificateDer<'static>],
+    builtin_roots: bool,
+) -> Result<Arc<ClientConfig>, api::Error> {
+    let mut store = RootCertStore::empty();
+    if builtin_roots {
+        store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    for root in roots {
+        store
+            .add(root.clone())
+            .map_err(|_| api::Error::InvalidRootCertificate)?;
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|_| api::Error::TlsFailed)?
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Perform the TLS handshake with `host` over `socket`. The certificate has to chain to one
+/// of the roots in `config`, and has to be valid for `host`.
+pub fn connect(
+    socket: &mut TcpStream,
+    config: Arc<ClientConfig>,
+    host: &str,
+) -> Result<ClientConnection, api::Error> {
+    let name = ServerName::try_from(host.to_owned()).map_err(|_| api::Error::InvalidUrl)?;
+    let mut session = ClientConnection::new(config, name).map_err(|_| api::Error::TlsFailed)?;
+    while session.is_handshaking() {
+        if let Err(e) = session.complete_io(socket) {
+            // rustls reports its own errors wrapped up in an `io::Error`.
+            return Err(e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+                .map(to_api_error)
+                .unwrap_or(api::Error::TlsFailed));
+        }
+    }
+    Ok(session)
+}
+
+/// Sort TLS failures into the ones that the client can tell apart.
+fn to_api_error(e: &rustls::Error) -> api::Error {
+    match e {
+        rustls::Error::InvalidCertificate(e) => match e {
+            CertificateError::UnknownIssuer => api::Error::CertificateUntrusted,
+            CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {
+                api::Error::CertificateNameMismatch
+            }
+            CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+                api::Error::CertificateExpired
+            }
+            CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+                api::Error::CertificateNotYetValid
+            }
+            CertificateError::Revoked => api::Error::CertificateRevoked,
+            _ => api::Error::CertificateInvalid,
+        },
+        _ => api::Error::TlsFailed,
+    }
+}
+
+/// Decrypt `data`, which was read from the socket, and pass the result to `decoder`.
+/// Anything that TLS needs to send in return, such as a reply to a key update, is written
+/// to `socket`.
+pub fn receive(
+    session: &mut ClientConnection,
+    socket: &TcpStream,
+    mut data: &[u8],
+    decoder: &mut Decoder,
+) -> Result<(), rustls::Error> {
+    let mut plaintext = [0u8; 4096];
+    // This runs at least once, so that anything left over from the handshake is drained.
+    loop {
+        if !data.is_empty() {
+            // Reading from a slice can't fail.
+            session.read_tls(&mut data).ok();
+        }
+        session.process_new_packets()?;
+        loop {
+            match session.reader().read(&mut plaintext) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => decoder.push(&plaintext[..len]),
+            }
+        }
+        if data.is_empty() {
+            break;
+        }
+    }
+    flush(session, socket).ok();
+    Ok(())
+}
+
+/// Encrypt `data` and write it to `socket`.
+pub fn send(
+    session: &mut ClientConnection,
+    socket: &TcpStream,
+    data: &[u8],
+) -> std::io::Result<()> {
+    session.writer().write_all(data)?;
+    flush(session, socket)
+}
+
+/// Write out everything that the session has waiting to be sent.
+fn flush(session: &mut ClientConnection, mut socket: &TcpStream) -> std::io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut socket)?;
+    }
+    Ok(())
+}